@@ -0,0 +1,165 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use clap::ValueEnum;
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageFormat, ImageReader};
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::openexr::OpenExrEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::pnm::PnmEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::codecs::webp::WebPEncoder;
+use crate::metadata::{self, Metadata};
+use crate::netpbm;
+use crate::palette::Palette;
+use smart_denoise::TransferFunction;
+
+/// File formats the binary reads and writes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum FileFormat {
+    ///8 or 16-bit, gray or colour, with or without alpha
+    Png,
+    ///8-bit gray or RGB, alpha is dropped
+    Jpeg,
+    ///8 or 16-bit or float, gray+alpha becomes RGBA
+    Tiff,
+    ///Lossless 8-bit
+    Webp,
+    ///8-bit
+    Bmp,
+    ///PGM or PPM, PAM with alpha, 8 or 16-bit
+    Pnm,
+    ///Portable float map, gray or RGB, alpha is dropped
+    Pfm,
+    ///OpenEXR, float RGB or RGBA
+    Exr,
+}
+
+impl FileFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        Some(match ext.as_str() {
+            "png" => FileFormat::Png,
+            "jpg" | "jpeg" => FileFormat::Jpeg,
+            "tif" | "tiff" => FileFormat::Tiff,
+            "webp" => FileFormat::Webp,
+            "bmp" => FileFormat::Bmp,
+            "pnm" | "pgm" | "ppm" | "pam" => FileFormat::Pnm,
+            "pfm" => FileFormat::Pfm,
+            "exr" => FileFormat::Exr,
+            _ => return None,
+        })
+    }
+
+    /// Closest image the encoder accepts, bit depth and alpha are kept where the format has them.
+    fn convert(self, img: DynamicImage) -> DynamicImage {
+        let gray = !img.color().has_color();
+        let alpha = img.color().has_alpha();
+        let float = matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+        let sixteen = img.color().bytes_per_pixel() / img.color().channel_count() == 2;
+        match self {
+            FileFormat::Png | FileFormat::Pnm if float => match alpha {
+                true => DynamicImage::ImageRgba16(img.to_rgba16()),
+                false => DynamicImage::ImageRgb16(img.to_rgb16()),
+            },
+            FileFormat::Png | FileFormat::Pnm | FileFormat::Pfm => img,
+            FileFormat::Tiff => match img {
+                DynamicImage::ImageLumaA8(_) => DynamicImage::ImageRgba8(img.to_rgba8()),
+                DynamicImage::ImageLumaA16(_) => DynamicImage::ImageRgba16(img.to_rgba16()),
+                img => img,
+            },
+            FileFormat::Jpeg => match gray {
+                true => DynamicImage::ImageLuma8(img.to_luma8()),
+                false => DynamicImage::ImageRgb8(img.to_rgb8()),
+            },
+            FileFormat::Webp | FileFormat::Bmp if float || sixteen => match (gray, alpha) {
+                (true, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+                (true, true) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+                (false, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+                (false, true) => DynamicImage::ImageRgba8(img.to_rgba8()),
+            },
+            FileFormat::Webp | FileFormat::Bmp => img,
+            FileFormat::Exr => match alpha {
+                true => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+                false => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+            },
+        }
+    }
+}
+
+/// Decodes any supported format, guessing it from the file content. Metadata is only read when `keep_metadata` is set.
+pub fn load(path: &Path, keep_metadata: bool) -> Result<(DynamicImage, Metadata), String> {
+    if netpbm::is_pfm(path) {
+        return Ok((netpbm::read_pfm(path)?, Metadata::default()));
+    }
+    let reader = ImageReader::open(path)
+        .map_err(|e| e.to_string())?
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    let is_png = reader.format() == Some(ImageFormat::Png);
+    let mut decoder = reader.into_decoder().map_err(|e| e.to_string())?;
+
+    let mut metadata = Metadata::default();
+    if keep_metadata {
+        metadata.icc_profile = decoder.icc_profile().map_err(|e| e.to_string())?;
+        metadata.exif = decoder.exif_metadata().map_err(|e| e.to_string())?;
+        if is_png {
+            metadata.png = Some(metadata::read_png_info(path)?);
+        }
+    }
+    let img = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    Ok((img, metadata))
+}
+
+/// Transfer function the file at `path` declares, only PNG has a way to.
+pub fn declared_transfer(path: &Path) -> Result<Option<TransferFunction>, String> {
+    let reader = ImageReader::open(path)
+        .map_err(|e| e.to_string())?
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    match reader.format() {
+        Some(ImageFormat::Png) => metadata::read_png_transfer(path),
+        _ => Ok(None),
+    }
+}
+
+/// Encodes `img` as `format`, converting it first when the format can't store it as it is.
+///
+/// ICC profile and EXIF go to every format able to hold them, PNG to PNG keeps all ancillary chunks.
+/// With a `palette` the output is an indexed PNG.
+pub fn save(img: DynamicImage, metadata: &Metadata, palette: Option<&Palette>, path: &Path, format: FileFormat) -> Result<(), String> {
+    let gray = !img.color().has_color();
+    let img = format.convert(img);
+    match (format, palette) {
+        (FileFormat::Png, _) if palette.is_some() || metadata.png.is_some() => {
+            return metadata::write_png(&img, path, metadata.png.as_ref(), palette);
+        }
+        (_, Some(_)) => return Err(format!("only PNG output can keep the palette, not {:?}", format)),
+        _ => {}
+    }
+    match format {
+        FileFormat::Pnm if !img.color().has_alpha() => return netpbm::write_pnm(&img, path),
+        FileFormat::Pfm => return netpbm::write_pfm(&img, path, gray),
+        _ => {}
+    }
+
+    let mut writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    match format {
+        FileFormat::Png => encode(&img, PngEncoder::new(&mut writer), metadata),
+        FileFormat::Jpeg => encode(&img, JpegEncoder::new(&mut writer), metadata),
+        FileFormat::Tiff => encode(&img, TiffEncoder::new(&mut writer), metadata),
+        FileFormat::Webp => encode(&img, WebPEncoder::new_lossless(&mut writer), metadata),
+        FileFormat::Bmp => encode(&img, BmpEncoder::new(&mut writer), metadata),
+        //Only reached with alpha, which needs PAM
+        FileFormat::Pnm => encode(&img, PnmEncoder::new(&mut writer), metadata),
+        FileFormat::Exr => encode(&img, OpenExrEncoder::new(&mut writer), metadata),
+        FileFormat::Pfm => unreachable!(),
+    }?;
+    writer.flush().map_err(|e| e.to_string())
+}
+
+fn encode(img: &DynamicImage, mut encoder: impl ImageEncoder, metadata: &Metadata) -> Result<(), String> {
+    metadata.apply(&mut encoder);
+    img.write_with_encoder(encoder).map_err(|e| e.to_string())
+}
@@ -0,0 +1,211 @@
+extern crate core;
+
+mod formats;
+mod metadata;
+mod netpbm;
+mod palette;
+
+use std::error::Error;
+use std::path::Path;
+use smart_denoise::{estimate_linear_image_noise, list_devices, Algo, AlphaMode, BackendPolicy, BorderMode, ColorSpace, DenoiseError, Denoiser, DenoiseParams, DeviceSelector, NonLocalMeansParams, Options, TransferFunction, UsingShader};
+use formats::FileFormat;
+use palette::Palette;
+use clap::Parser;
+
+/// Simple program to denoise an image
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the input file: png, jpeg, tiff, webp, bmp, pnm, pfm or exr
+    #[clap(short, long, required_unless_present = "list_devices")]
+    filename_in: Option<String>,
+
+    /// Path to the output file. Format comes from the extension unless --format is given, bit depth is kept where the format allows
+    #[clap(long, required_unless_present = "list_devices")]
+    filename_out: Option<String>,
+
+    /// Output file format, overrides the --filename-out extension
+    #[clap(long, value_enum)]
+    format: Option<FileFormat>,
+
+    /// Don't copy ICC profile, EXIF and other PNG metadata from the input file
+    #[clap(long)]
+    strip_metadata: bool,
+
+    /// Map the result back to the palette of an indexed PNG input instead of writing RGB(A)
+    #[clap(long)]
+    keep_palette: bool,
+
+    /// Which shader type to use
+    #[clap(long, required_unless_present = "list_devices")]
+    shader_type: Option<UsingShader>,
+
+    ///Sigma parameter
+    #[clap(long)]
+    sigma: Option<f32>,
+
+    ///kSigma parameter
+    #[clap(long)]
+    kSigma: Option<f32>,
+
+    ///threshold parameter
+    #[clap(long)]
+    threshold: Option<f32>,
+
+    ///Estimate the noise of the input, in the linear light of the transfer function, and derive sigma and threshold from it
+    #[clap(long, conflicts_with_all = &["sigma", "kSigma", "threshold"])]
+    auto: bool,
+
+    ///Process denoise in HSV (H & V actually) space
+    #[clap(long)]
+    use_hsv: bool,
+
+    ///Colour space to filter in, luma/chroma ones take --chroma-sigma and --chroma-threshold
+    #[clap(long, value_enum, default_value_t = ColorSpace::Rgb)]
+    color_space: ColorSpace,
+
+    ///Sigma of the chroma channels, defaults to sigma
+    #[clap(long)]
+    chroma_sigma: Option<f32>,
+
+    ///Threshold of the chroma channels, defaults to threshold
+    #[clap(long)]
+    chroma_threshold: Option<f32>,
+
+    ///Using algorythm
+    #[clap(long, required_unless_present = "list_devices")]
+    algo: Option<Algo>,
+
+    ///Patch radius of non-local means, defaults to 1
+    #[clap(long)]
+    patch_radius: Option<u32>,
+
+    ///Search radius of non-local means, defaults to 7
+    #[clap(long)]
+    search_radius: Option<u32>,
+
+    ///Filtering strength of non-local means relative to the white level, a bit above the noise level. Defaults to 0.05, or comes from --auto
+    #[clap(long)]
+    strength: Option<f32>,
+
+    ///What to do with the alpha channel
+    #[clap(long, value_enum, default_value_t = AlphaMode::Preserve)]
+    alpha_mode: AlphaMode,
+
+    ///Where pixels outside the image come from
+    #[clap(long, value_enum, default_value_t = BorderMode::Clamp)]
+    border_mode: BorderMode,
+
+    ///Sample value of full intensity, e.g. 1023 for 10-bit data in a 16-bit png. Defaults to the bit depth maximum
+    #[clap(long)]
+    white_level: Option<f32>,
+
+    ///Transfer function of the input, colour is filtered in linear light: linear, srgb, rec709 or a gamma exponent like 2.2. Defaults to the PNG sRGB or gAMA chunk, linear otherwise
+    #[clap(long)]
+    transfer: Option<TransferFunction>,
+
+    ///Which backend to run on
+    #[clap(long, value_enum, default_value_t = BackendPolicy::PreferGpu)]
+    backend: BackendPolicy,
+
+    ///Vulkan device to use: index from --list-devices, type (discrete-gpu, integrated-gpu, virtual-gpu, cpu, other) or part of the device name
+    #[clap(long)]
+    device: Option<DeviceSelector>,
+
+    ///GPU memory in MiB a single pass may use, larger images are processed in tiles. Defaults to half of the device memory
+    #[clap(long)]
+    memory_budget: Option<u64>,
+
+    ///Print available Vulkan devices and exit
+    #[clap(long)]
+    list_devices: bool
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if let Err(e) = run(args) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if args.list_devices {
+        for device in list_devices()? {
+            println!("{}", device);
+        }
+        return Ok(());
+    }
+    //Presence is enforced by clap unless --list-devices is given
+    let (filename_in, filename_out, shader_type, algo) = match (args.filename_in, args.filename_out, args.shader_type, args.algo) {
+        (Some(filename_in), Some(filename_out), Some(shader_type), Some(algo)) => (filename_in, filename_out, shader_type, algo),
+        _ => unreachable!()
+    };
+
+    let path_out = Path::new(&filename_out);
+    let format = match args.format.or_else(|| FileFormat::from_path(path_out)) {
+        Some(format) => format,
+        None => return Err(format!("can't tell output format from {:?}, use --format", filename_out).into()),
+    };
+
+    let path_in = Path::new(&filename_in);
+    let palette = match args.keep_palette {
+        true if format != FileFormat::Png => return Err("--keep-palette needs a PNG output".into()),
+        true => Some(Palette::read(path_in)?.ok_or("--keep-palette needs an indexed PNG input")?),
+        false => None,
+    };
+    let (img, metadata) = formats::load(path_in, !args.strip_metadata)?;
+    let transfer = match args.transfer {
+        Some(transfer) => transfer,
+        None => formats::declared_transfer(path_in)?.unwrap_or_default(),
+    };
+
+    let denoise_params: DenoiseParams = if args.auto {
+        //Noise is measured in the light the filters compare
+        let estimate = estimate_linear_image_noise(&img, transfer, args.white_level)?;
+        let params = estimate.params();
+        eprintln!("Estimated {}: sigma {:.2}, threshold {:.4}, non-local means strength {:.4}",
+                  estimate, params.sigma(), params.threshold(), params.non_local_means().strength);
+        params
+    } else {
+        match (args.sigma, args.kSigma, args.threshold) {
+            (None, None, None) => DenoiseParams::default(),
+            (Some(sigma), Some(k_sigma), Some(threshold)) => DenoiseParams::new(sigma, k_sigma, threshold),
+            _ => return Err(DenoiseError::InvalidParameter("provide all 3 parameters: sigma, kSigma and threshold".to_string()).into()),
+        }
+    }.with_alpha_mode(args.alpha_mode)
+     .with_border_mode(args.border_mode)
+     .with_color_space(args.color_space);
+    let denoise_params = match (args.chroma_sigma, args.chroma_threshold) {
+        (None, None) => denoise_params,
+        (sigma, threshold) => denoise_params.with_chroma(sigma.unwrap_or(denoise_params.sigma()), threshold.unwrap_or(denoise_params.threshold())),
+    };
+    let non_local_means = denoise_params.non_local_means();
+    let denoise_params = denoise_params.with_non_local_means(NonLocalMeansParams {
+        patch_radius: args.patch_radius.unwrap_or(non_local_means.patch_radius),
+        search_radius: args.search_radius.unwrap_or(non_local_means.search_radius),
+        strength: args.strength.unwrap_or(non_local_means.strength),
+    });
+    let denoise_params = match args.white_level {
+        Some(white_level) => denoise_params.with_white_level(white_level),
+        None => denoise_params
+    };
+
+    let denoiser = Denoiser::with_device_selector(args.backend, &args.device.unwrap_or_default())?;
+    let denoiser = match args.memory_budget {
+        Some(mib) => denoiser.with_memory_budget(mib * 1024 * 1024),
+        None => denoiser
+    };
+    match (denoiser.device(), denoiser.fallback_reason()) {
+        (Some(device), _) => eprintln!("Using GPU backend: {}", device.physical_device().properties().device_name),
+        (None, Some(reason)) => eprintln!("Using CPU backend, GPU is unavailable: {}", reason),
+        (None, None) => eprintln!("Using CPU backend"),
+    }
+
+    let denoise_params = denoise_params.with_transfer_function(transfer);
+    let options = Options { shader_type, params: denoise_params, use_hsv: args.use_hsv, algo };
+    let result = denoiser.denoise_image(&img, &options)?;
+    formats::save(result, &metadata, palette.as_ref(), path_out, format)?;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use image::{DynamicImage, ImageEncoder};
+use png::{BitDepth, ColorType, Info};
+use smart_denoise::TransferFunction;
+use crate::palette::Palette;
+
+/// Ancillary data carried from the input file to the output one.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub icc_profile: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+    /// Ancillary chunks of a PNG input, written back as they are when the output is PNG too
+    pub png: Option<Info<'static>>,
+}
+
+impl Metadata {
+    /// Hands ICC profile and EXIF to an encoder of the `image` crate, formats that can't store them just don't get them.
+    pub fn apply(&self, encoder: &mut impl ImageEncoder) {
+        if let Some(icc_profile) = &self.icc_profile {
+            let _ = encoder.set_icc_profile(icc_profile.clone());
+        }
+        if let Some(exif) = &self.exif {
+            let _ = encoder.set_exif_metadata(exif.clone());
+        }
+    }
+}
+
+/// PNG chunks of `path`, including the text chunks following the image data.
+pub fn read_png_info(path: &Path) -> Result<Info<'static>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut reader = png::Decoder::new(BufReader::new(file)).read_info().map_err(|e| e.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size().ok_or("PNG image is too large")?];
+    reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+    reader.finish().map_err(|e| e.to_string())?;
+    Ok(reader.info().clone())
+}
+
+/// Transfer function declared by the sRGB or gAMA chunk of the PNG at `path`.
+pub fn read_png_transfer(path: &Path) -> Result<Option<TransferFunction>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let reader = png::Decoder::new(BufReader::new(file)).read_info().map_err(|e| e.to_string())?;
+    let info = reader.info();
+    if info.srgb.is_some() {
+        return Ok(Some(TransferFunction::Srgb));
+    }
+    //gAMA holds the encoding exponent
+    Ok(info.source_gamma.or(info.gama_chunk).map(|gamma| TransferFunction::Gamma(1.0 / gamma.into_value())))
+}
+
+/// Writes an 8 or 16-bit `img` with the colour management, physical size and text chunks of `source`,
+/// or as indices into `palette` when one is given.
+///
+/// Transparency, background and significant bits of `source` describe the input samples, so they aren't copied.
+pub fn write_png(img: &DynamicImage, path: &Path, source: Option<&Info<'static>>, palette: Option<&Palette>) -> Result<(), String> {
+    let (color_type, bit_depth) = match (palette, img) {
+        (Some(palette), _) => (ColorType::Indexed, palette.bit_depth),
+        (None, DynamicImage::ImageLuma8(_)) => (ColorType::Grayscale, BitDepth::Eight),
+        (None, DynamicImage::ImageLumaA8(_)) => (ColorType::GrayscaleAlpha, BitDepth::Eight),
+        (None, DynamicImage::ImageRgb8(_)) => (ColorType::Rgb, BitDepth::Eight),
+        (None, DynamicImage::ImageRgba8(_)) => (ColorType::Rgba, BitDepth::Eight),
+        (None, DynamicImage::ImageLuma16(_)) => (ColorType::Grayscale, BitDepth::Sixteen),
+        (None, DynamicImage::ImageLumaA16(_)) => (ColorType::GrayscaleAlpha, BitDepth::Sixteen),
+        (None, DynamicImage::ImageRgb16(_)) => (ColorType::Rgb, BitDepth::Sixteen),
+        (None, DynamicImage::ImageRgba16(_)) => (ColorType::Rgba, BitDepth::Sixteen),
+        (None, img) => return Err(format!("PNG can't store {:?}", img.color())),
+    };
+
+    let mut info = Info::with_size(img.width(), img.height());
+    info.color_type = color_type;
+    info.bit_depth = bit_depth;
+    if let Some(source) = source {
+        info.pixel_dims = source.pixel_dims;
+        //Decoder leaves these out when an ICC profile or sRGB chunk overrides them, the file still had them
+        info.source_gamma = source.source_gamma.or(source.gama_chunk);
+        info.source_chromaticities = source.source_chromaticities.or(source.chrm_chunk);
+        info.srgb = source.srgb;
+        info.icc_profile = source.icc_profile.clone();
+        info.exif_metadata = source.exif_metadata.clone();
+        info.coding_independent_code_points = source.coding_independent_code_points;
+        info.mastering_display_color_volume = source.mastering_display_color_volume;
+        info.content_light_level = source.content_light_level;
+        info.uncompressed_latin1_text = source.uncompressed_latin1_text.clone();
+        info.compressed_latin1_text = source.compressed_latin1_text.clone();
+        info.utf8_text = source.utf8_text.clone();
+    }
+    if let Some(palette) = palette {
+        info.palette = Some(palette.rgb.clone().into());
+        info.trns = palette.alpha.clone().map(Into::into);
+    }
+
+    let writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    let mut writer = png::Encoder::with_info(writer, info)
+        .and_then(|encoder| encoder.write_header())
+        .map_err(|e| e.to_string())?;
+    let data: Vec<u8> = match (palette, bit_depth) {
+        (Some(palette), _) => palette.quantize(img),
+        //PNG stores 16-bit samples big endian
+        (None, BitDepth::Sixteen) => img.as_bytes().chunks_exact(2).flat_map(|b| u16::from_ne_bytes([b[0], b[1]]).to_be_bytes()).collect(),
+        (None, _) => img.as_bytes().to_vec(),
+    };
+    writer.write_image_data(&data).and_then(|_| writer.finish()).map_err(|e| e.to_string())
+}
@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use image::{DynamicImage, ImageBuffer};
+
+/// Reads a Portable Float Map, rows are stored bottom to top. Grayscale `Pf` maps come back as RGB with equal channels.
+pub fn read_pfm(path: &Path) -> Result<DynamicImage, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let mut header = Vec::new();
+    //Magic, dimensions and scale, each field ends with a single whitespace
+    let mut fields = Vec::new();
+    while fields.len() < 4 {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte).map_err(|_| "truncated PFM header".to_string())?;
+        if byte[0].is_ascii_whitespace() {
+            if !header.is_empty() {
+                fields.push(String::from_utf8_lossy(&header).into_owned());
+                header.clear();
+            }
+        } else {
+            header.push(byte[0]);
+        }
+    }
+    let channels = match fields[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        magic => return Err(format!("not a PFM file, magic is {:?}", magic)),
+    };
+    let parse = |s: &str| s.parse::<u32>().map_err(|_| format!("invalid PFM dimension {:?}", s));
+    let (width, height) = (parse(&fields[1])?, parse(&fields[2])?);
+    let scale: f32 = fields[3].parse().map_err(|_| format!("invalid PFM scale {:?}", fields[3]))?;
+
+    let row_len = width as usize * channels;
+    let mut bytes = vec![0u8; row_len * height as usize * 4];
+    reader.read_exact(&mut bytes).map_err(|_| "truncated PFM data".to_string())?;
+    let samples: Vec<f32> = bytes.chunks_exact(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if scale < 0.0 { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }
+        })
+        .collect();
+    let top_down: Vec<f32> = samples.chunks_exact(row_len).rev().flatten().copied().collect();
+
+    match channels {
+        3 => ImageBuffer::from_raw(width, height, top_down).map(DynamicImage::ImageRgb32F),
+        //The image crate has no grayscale float type
+        _ => ImageBuffer::from_raw(width, height, top_down.iter().flat_map(|v| [*v; 3]).collect()).map(DynamicImage::ImageRgb32F),
+    }.ok_or_else(|| "PFM dimensions don't match its data".to_string())
+}
+
+/// Writes `img` as little endian PFM, grayscale when `gray` is set, alpha is dropped.
+pub fn write_pfm(img: &DynamicImage, path: &Path, gray: bool) -> Result<(), String> {
+    let rgb = img.to_rgb32f();
+    let (width, height) = rgb.dimensions();
+    let channels = if gray { 1 } else { 3 };
+    let mut writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    let magic = if gray { "Pf" } else { "PF" };
+    write!(writer, "{}\n{} {}\n-1.0\n", magic, width, height).map_err(|e| e.to_string())?;
+    for row in rgb.as_raw().chunks_exact(width as usize * 3).rev() {
+        for px in row.chunks_exact(3) {
+            for v in &px[..channels] {
+                writer.write_all(&v.to_le_bytes()).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
+/// Whether the file starts with a PFM magic.
+pub fn is_pfm(path: &Path) -> bool {
+    let mut magic = Vec::with_capacity(3);
+    match File::open(path).and_then(|f| f.take(3).read_to_end(&mut magic)) {
+        Ok(3) => (&magic[..2] == b"PF" || &magic[..2] == b"Pf") && magic[2].is_ascii_whitespace(),
+        _ => false,
+    }
+}
+
+/// Writes an 8 or 16-bit image without alpha as binary PGM or PPM, the image crate only writes 16-bit samples as PAM.
+pub fn write_pnm(img: &DynamicImage, path: &Path) -> Result<(), String> {
+    let mut writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    let (magic, maxval) = match img {
+        DynamicImage::ImageLuma8(_) => ("P5", 0xff),
+        DynamicImage::ImageLuma16(_) => ("P5", 0xffff),
+        DynamicImage::ImageRgb8(_) => ("P6", 0xff),
+        DynamicImage::ImageRgb16(_) => ("P6", 0xffff),
+        img => return Err(format!("PGM and PPM can't store {:?}", img.color())),
+    };
+    write!(writer, "{}\n{} {}\n{}\n", magic, img.width(), img.height(), maxval).map_err(|e| e.to_string())?;
+    match img {
+        DynamicImage::ImageLuma16(img) => img.as_raw().iter().try_for_each(|v| writer.write_all(&v.to_be_bytes())),
+        DynamicImage::ImageRgb16(img) => img.as_raw().iter().try_for_each(|v| writer.write_all(&v.to_be_bytes())),
+        img => writer.write_all(img.as_bytes()),
+    }.map_err(|e| e.to_string())?;
+    writer.flush().map_err(|e| e.to_string())
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use image::DynamicImage;
+use png::{BitDepth, ColorType};
+
+/// Colour table of an indexed PNG, used to bring the denoised RGB(A) result back to indices.
+#[derive(Debug, Clone)]
+pub struct Palette {
+    /// RGB triples as stored in PLTE
+    pub rgb: Vec<u8>,
+    /// Alpha of the first entries from tRNS, the others are opaque
+    pub alpha: Option<Vec<u8>>,
+    pub bit_depth: BitDepth,
+}
+
+impl Palette {
+    /// Palette of `path`, `None` when the image isn't indexed.
+    pub fn read(path: &Path) -> Result<Option<Palette>, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let reader = png::Decoder::new(BufReader::new(file)).read_info().map_err(|e| e.to_string())?;
+        let info = reader.info();
+        if info.color_type != ColorType::Indexed {
+            return Ok(None);
+        }
+        Ok(info.palette.as_ref().map(|rgb| Palette {
+            rgb: rgb.to_vec(),
+            alpha: info.trns.as_ref().map(|trns| trns.to_vec()),
+            bit_depth: info.bit_depth,
+        }))
+    }
+
+    fn entry(&self, index: usize) -> [u8; 4] {
+        let rgb = &self.rgb[index * 3..index * 3 + 3];
+        let alpha = self.alpha.as_ref().and_then(|alpha| alpha.get(index)).copied().unwrap_or(u8::MAX);
+        [rgb[0], rgb[1], rgb[2], alpha]
+    }
+
+    /// Index of the entry closest to `px` in RGBA.
+    fn nearest(&self, px: [u8; 4]) -> u8 {
+        let distance = |entry: [u8; 4]| entry.iter().zip(px).map(|(&a, b)| (a as i32 - b as i32).pow(2)).sum::<i32>();
+        (0..self.rgb.len() / 3).min_by_key(|&i| distance(self.entry(i))).unwrap_or(0) as u8
+    }
+
+    /// Rows of `img` mapped to the nearest palette entries and packed to the palette bit depth.
+    pub fn quantize(&self, img: &DynamicImage) -> Vec<u8> {
+        let img = img.to_rgba8();
+        let bits = self.bit_depth as usize;
+        let row_bytes = (img.width() as usize * bits).div_ceil(8);
+        let mut cache = HashMap::new();
+        let mut data = vec![0; row_bytes * img.height() as usize];
+        for (row, data_row) in img.rows().zip(data.chunks_exact_mut(row_bytes)) {
+            for (x, px) in row.enumerate() {
+                let index = *cache.entry(px.0).or_insert_with(|| self.nearest(px.0));
+                //Sub-byte indices are packed from the most significant bit
+                let bit = x * bits;
+                data_row[bit / 8] |= index << (8 - bits - bit % 8);
+            }
+        }
+        data
+    }
+}
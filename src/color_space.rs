@@ -0,0 +1,72 @@
+//! Conversions between linear RGB and the luma/chroma spaces of [`ColorSpace`], the CPU twin of `toFilterSpace`
+//! and `fromFilterSpace` in `templates/shader_fetch.mustache`.
+
+//Matrices are written as published so they're easy to check against the references
+#![allow(clippy::excessive_precision)]
+
+use crate::ColorSpace;
+
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [[0.4124564, 0.3575761, 0.1804375], [0.2126729, 0.7151522, 0.0721750], [0.0193339, 0.1191920, 0.9503041]];
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [[3.2404542, -1.5371385, -0.4985314], [-0.9692660, 1.8760108, 0.0415560], [0.0556434, -0.2040259, 1.0572252]];
+const D65_WHITE: [f32; 3] = [0.95047, 1.0, 1.08883];
+const RGB_TO_LMS: [[f32; 3]; 3] = [[0.4122214708, 0.5363325363, 0.0514459929], [0.2119034982, 0.6806995451, 0.1073969566],
+                                   [0.0883024619, 0.2817188376, 0.6299787005]];
+const LMS_TO_OKLAB: [[f32; 3]; 3] = [[0.2104542553, 0.7936177850, -0.0040720468], [1.9779984951, -2.4285922050, 0.4505937099],
+                                     [0.0259040371, 0.7827717662, -0.8086757660]];
+const OKLAB_TO_LMS: [[f32; 3]; 3] = [[1.0, 0.3963377774, 0.2158037573], [1.0, -0.1055613458, -0.0638541728], [1.0, -0.0894841775, -1.2914855480]];
+const LMS_TO_RGB: [[f32; 3]; 3] = [[4.0767416621, -3.3077115913, 0.2309699292], [-1.2684380046, 2.6097574011, -0.3413193965],
+                                   [-0.0041960863, -0.7034186147, 1.7076147010]];
+const DELTA: f32 = 6.0 / 29.0;
+/// Rec.709 luma weights of red and blue
+const KR: f32 = 0.2126;
+const KB: f32 = 0.0722;
+
+fn mul(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+/// CIELab companding, `t` is relative to the white point.
+fn lab_f(t: f32) -> f32 {
+    if t > DELTA * DELTA * DELTA { t.cbrt() } else { t / (3.0 * DELTA * DELTA) + 4.0 / 29.0 }
+}
+
+fn lab_f_inv(t: f32) -> f32 {
+    if t > DELTA { t * t * t } else { 3.0 * DELTA * DELTA * (t - 4.0 / 29.0) }
+}
+
+impl ColorSpace {
+    /// Linear RGB to this space, luma first.
+    pub(crate) fn to_filter_space(self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Rgb => rgb,
+            ColorSpace::YCbCr => {
+                let y = KR * rgb[0] + (1.0 - KR - KB) * rgb[1] + KB * rgb[2];
+                [y, (rgb[2] - y) / (2.0 * (1.0 - KB)), (rgb[0] - y) / (2.0 * (1.0 - KR))]
+            }
+            ColorSpace::Lab => {
+                let xyz = mul(&SRGB_TO_XYZ, rgb);
+                let [fx, fy, fz] = [0, 1, 2].map(|i| lab_f(xyz[i] / D65_WHITE[i]));
+                [1.16 * fy - 0.16, 5.0 * (fx - fy), 2.0 * (fy - fz)]
+            }
+            ColorSpace::Oklab => mul(&LMS_TO_OKLAB, mul(&RGB_TO_LMS, rgb).map(f32::cbrt)),
+        }
+    }
+
+    /// Inverse of [`to_filter_space`](Self::to_filter_space).
+    pub(crate) fn to_rgb(self, c: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Rgb => c,
+            ColorSpace::YCbCr => {
+                let r = c[0] + 2.0 * (1.0 - KR) * c[2];
+                let b = c[0] + 2.0 * (1.0 - KB) * c[1];
+                [r, (c[0] - KR * r - KB * b) / (1.0 - KR - KB), b]
+            }
+            ColorSpace::Lab => {
+                let fy = (c[0] + 0.16) / 1.16;
+                let f = [fy + c[1] / 5.0, fy, fy - c[2] / 2.0];
+                mul(&XYZ_TO_SRGB, [0, 1, 2].map(|i| lab_f_inv(f[i]) * D65_WHITE[i]))
+            }
+            ColorSpace::Oklab => mul(&LMS_TO_RGB, mul(&OKLAB_TO_LMS, c).map(|v| v * v * v)),
+        }
+    }
+}
@@ -1,79 +1,63 @@
 use std::sync::Arc;
-use std::time::Instant;
-use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
 use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
-use vulkano::device::{Device, Queue};
-use vulkano::image::{ImageAccess, ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::device::Device;
+use vulkano::image::{ImageAccess, StorageImage};
 use vulkano::image::view::ImageView;
 use vulkano::pipeline::{ComputePipeline, Pipeline, PipelineBindPoint};
 use vulkano::sampler::Sampler;
-use vulkano::sync;
-use vulkano::sync::GpuFuture;
 use vulkano::format::Format;
-use crate::{Algo, DenoiseParams, ShaderParams, UsingShader};
-
-pub(crate) fn denoise(device: Arc<Device>, queue: Arc<Queue>, input_img: Arc<StorageImage>, result_img: Arc<StorageImage>,
-                      sampler: Arc<Sampler>, denoise_params: DenoiseParams, use_hsv: bool, algo: Algo) {
-
-    let shader = crate::generated::get_denoise_shader(device.clone(), result_img.format(), UsingShader::Compute, use_hsv, algo);
-
-    let compute_pipeline = ComputePipeline::new(device.clone(), shader.entry_point("main").unwrap(), &(), None, |_| {})
-            .expect("failed to create compute pipeline");
-
-    let input_view = ImageView::new_default(input_img.clone()).unwrap();
-    let output_view = ImageView::new_default(result_img).unwrap();
-
-    let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
-
-    let img_w = input_img.dimensions().width();
-    let img_h = input_img.dimensions().height();
-
-    let items = match algo {
-        Algo::Smart => vec![WriteDescriptorSet::image_view_sampler(0, input_view, sampler),
-                            WriteDescriptorSet::image_view(1, output_view)],
-        Algo::Radial => {
-            let inter_res_img = StorageImage::with_usage(device.clone(),
-                                                      ImageDimensions::Dim2d { width: img_w, height: img_h, array_layers: 1},
-                                                         vulkano::format::Format::R32_SFLOAT,
-                                                      ImageUsage {
-                                                          transfer_source: true,
-                                                          transfer_destination: false,
-                                                          sampled: false,
-                                                          storage: true,
-                                                          color_attachment: true,
-                                                          depth_stencil_attachment: false,
-                                                          transient_attachment: false,
-                                                          input_attachment: false
-                                                      },
-                                                      ImageCreateFlags::none(),
-                                                      Some(queue.family())).unwrap();
-            vec![WriteDescriptorSet::image_view_sampler(0, input_view, sampler),
-                 //WriteDescriptorSet::image_view(1, ImageView::new_default(inter_res_img).unwrap()),
-                 WriteDescriptorSet::image_view(2, output_view)]
-        }
-    };
-
-    let set = PersistentDescriptorSet::new(layout.clone(), items).unwrap();
-
-    let push_constants = ShaderParams::new(img_w, img_h, denoise_params);
-
-    //Computation itself
-    let now = Instant::now();
-    let command_buffer = {
-        let mut builder =
-                 AutoCommandBufferBuilder::primary(device.clone(), queue.family(), CommandBufferUsage::OneTimeSubmit).unwrap();
-        builder
-            .bind_pipeline_compute(compute_pipeline.clone())
-            .bind_descriptor_sets(PipelineBindPoint::Compute, compute_pipeline.layout().clone(), 0, set.clone())
-            .push_constants(compute_pipeline.layout().clone(), 0, push_constants)
-            .dispatch([img_w / 8, img_h / 8, 1]).unwrap();
-        builder.build().unwrap()
+use crate::{Algo, ColorSpace, DenoiseError, ShaderParams, UsingShader};
+use crate::denoiser::{guide_view, ImageBindings};
+
+/// `local_size_x` and `local_size_y` of the compute shaders
+const WORKGROUP_SIZE: u32 = 8;
+
+pub(crate) fn create_pipeline(device: Arc<Device>, format: Format, use_hsv: bool, color_space: ColorSpace, algo: Algo, guided: bool)
+    -> Result<Arc<ComputePipeline>, DenoiseError> {
+    let shader = crate::generated::get_denoise_shader(device.clone(), format, UsingShader::Compute, use_hsv, color_space, algo, guided)?;
+    let entry_point = shader.entry_point("main")
+        .ok_or_else(|| DenoiseError::PipelineCreation("no main entry point in compute shader".to_string()))?;
+
+    Ok(ComputePipeline::new(device, entry_point, &(), None, |_| {})?)
+}
+
+/// Descriptor set binding `input_img` and `guide_img` through `sampler` and `result_img` as storage image.
+pub(crate) fn bind(compute_pipeline: &Arc<ComputePipeline>, input_img: Arc<StorageImage>, result_img: Arc<StorageImage>,
+                   guide_img: Option<Arc<StorageImage>>, sampler: Arc<Sampler>, algo: Algo) -> Result<ImageBindings, DenoiseError> {
+    let input_view = ImageView::new_default(input_img.clone())?;
+    let output_view = ImageView::new_default(result_img.clone())?;
+
+    let layout = compute_pipeline.layout().set_layouts().get(0)
+        .ok_or_else(|| DenoiseError::PipelineCreation("compute shader has no descriptor set".to_string()))?;
+
+    let mut items = match algo {
+        Algo::Smart | Algo::NonLocalMeans => vec![WriteDescriptorSet::image_view_sampler(0, input_view, sampler.clone()),
+                                                  WriteDescriptorSet::image_view(1, output_view)],
+        Algo::Radial => vec![WriteDescriptorSet::image_view_sampler(0, input_view, sampler.clone()),
+                             //WriteDescriptorSet::image_view(1, ImageView::new_default(inter_res_img).unwrap()),
+                             WriteDescriptorSet::image_view(2, output_view)]
     };
-    let future = sync::now(device.clone())
-        .then_execute(queue.clone(), command_buffer)
-        .unwrap()
-        .then_signal_fence_and_flush()
-        .unwrap();
-    future.wait(None).unwrap();
-    #[cfg(debug_assertions)] eprintln!("Execute computation itself taken {} milliseconds", now.elapsed().as_millis());
-}
\ No newline at end of file
+    //Only the guided Smart shader reads guides
+    if let Some(guide_img) = guide_img.clone() {
+        items.push(WriteDescriptorSet::image_view_sampler(2, guide_view(guide_img)?, sampler));
+    }
+
+    let set = PersistentDescriptorSet::new(layout.clone(), items)?;
+    Ok(ImageBindings { input_img, result_img, guide_img, set, framebuffer: None })
+}
+
+/// Records the dispatch filtering `bindings.input_img` into `bindings.result_img`.
+pub(crate) fn record(builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, compute_pipeline: Arc<ComputePipeline>,
+                     bindings: &ImageBindings, push_constants: ShaderParams) -> Result<(), DenoiseError> {
+    //Output may be a tile of the input
+    let img_w = bindings.result_img.dimensions().width();
+    let img_h = bindings.result_img.dimensions().height();
+
+    builder
+        .bind_pipeline_compute(compute_pipeline.clone())
+        .bind_descriptor_sets(PipelineBindPoint::Compute, compute_pipeline.layout().clone(), 0, bindings.set.clone())
+        .push_constants(compute_pipeline.layout().clone(), 0, push_constants)
+        .dispatch([img_w.div_ceil(WORKGROUP_SIZE), img_h.div_ceil(WORKGROUP_SIZE), 1])?;
+    Ok(())
+}
@@ -0,0 +1,531 @@
+//! Rust port of the Smart, Radial and non-local means shaders for machines without a Vulkan driver, guided Smart included.
+//!
+//! Follows `denoise_shader_smart.mustache`, `denoise_shader_radial.mustache` and `denoise_shader_nonlocalmeans.mustache`
+//! step by step, sampling the input with the same bilinear filtering and addressing the GPU samplers use, so results
+//! stay within the tolerance documented on [`UsingShader::Cpu`](crate::UsingShader::Cpu).
+
+use std::f32::consts::PI;
+use rayon::prelude::*;
+use crate::{Algo, AlphaMode, BorderMode, ColorSpace, DenoiseError, DenoiseParams, Denoiseable, NonLocalMeansParams, RadialParams,
+            TransferFunction};
+
+const INV_SQRT_OF_2PI: f32 = 0.398_942_3;
+const INV_PI: f32 = 0.318_309_87;
+const EPSILON: f32 = 1e-10;
+
+type Pixel = [f32; 4];
+
+/// Normalised input image, every pixel padded to 4 samples.
+struct Texture {
+    width: usize,
+    height: usize,
+    /// Number of leading samples that are interpolated, the rest stay zero
+    samples: usize,
+    border_mode: BorderMode,
+    data: Vec<Pixel>,
+}
+
+/// Index of the texel the sampler address mode of `border_mode` reads for `i`.
+pub(crate) fn address(i: isize, size: usize, border_mode: BorderMode) -> usize {
+    let size = size as isize;
+    (match border_mode {
+        BorderMode::Clamp | BorderMode::Constant => i.clamp(0, size - 1),
+        BorderMode::Mirror => {
+            let i = i.rem_euclid(2 * size);
+            if i < size { i } else { 2 * size - 1 - i }
+        }
+        BorderMode::Wrap => i.rem_euclid(size),
+    }) as usize
+}
+
+impl Texture {
+    fn texel(&self, x: isize, y: isize) -> Pixel {
+        let x = address(x, self.width, self.border_mode);
+        let y = address(y, self.height, self.border_mode);
+        self.data[y * self.width + x]
+    }
+
+    /// `isCounted` of the shaders, outside samples are skipped in [`BorderMode::Constant`] mode.
+    fn is_counted(&self, x: f32, y: f32) -> bool {
+        self.border_mode != BorderMode::Constant
+            || (0.0..=self.width as f32).contains(&x) && (0.0..=self.height as f32).contains(&y)
+    }
+
+    /// Bilinear sample at pixel coordinates, texel centers are at integer + 0.5 like in GLSL.
+    fn sample(&self, x: f32, y: f32) -> Pixel {
+        let u = x - 0.5;
+        let v = y - 0.5;
+        let x0 = u.floor();
+        let y0 = v.floor();
+        let fx = u - x0;
+        let fy = v - y0;
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let p00 = self.texel(x0, y0);
+        let p10 = self.texel(x0 + 1, y0);
+        let p01 = self.texel(x0, y0 + 1);
+        let p11 = self.texel(x0 + 1, y0 + 1);
+        let mut res = [0.0; 4];
+        for c in 0..self.samples {
+            let top = p00[c] + (p10[c] - p00[c]) * fx;
+            let bottom = p01[c] + (p11[c] - p01[c]) * fx;
+            res[c] = top + (bottom - top) * fy;
+        }
+        res
+    }
+}
+
+/// Difference of two pixels, either per channel or in hue-value space.
+#[derive(Copy, Clone)]
+enum Diff {
+    Channels(Pixel),
+    Hv([f32; 2]),
+}
+
+fn rgb_to_hv(rgb: &Pixel) -> [f32; 2] {
+    // RGB [0..1] to Hue-Value [0..1]
+    // Based on work by Sam Hocevar and Emil Persson
+    let p = if rgb[1] < rgb[2] { [rgb[2], rgb[1], -1.0, 2.0 / 3.0] } else { [rgb[1], rgb[2], 0.0, -1.0 / 3.0] };
+    let q = if rgb[0] < p[0] { [p[0], p[1], p[3], rgb[0]] } else { [rgb[0], p[1], p[2], p[0]] };
+    let c = q[0] - q[3].min(q[1]);
+    let h = ((q[3] - q[1]) / (6.0 * c + EPSILON) + q[2]).abs();
+    [h, q[0]]
+}
+
+fn diff_hv(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    let h = (a[0] - b[0]).abs();
+    [(1.0 - h).min(h), (a[1] - b[1]).abs()]
+}
+
+/// Spatial and range falloff of the weights of one group of channels.
+struct Falloff {
+    inv_sigma_qx2: f32,
+    inv_sigma_qx2_pi: f32,
+    inv_threshold_sqx2: f32,
+    inv_threshold_sqrt2_pi: f32,
+}
+
+impl Falloff {
+    fn new(sigma: f32, threshold: f32) -> Self {
+        let inv_sigma_qx2 = 0.5 / (sigma * sigma);
+        Self {
+            inv_sigma_qx2,
+            inv_sigma_qx2_pi: INV_PI * inv_sigma_qx2,
+            inv_threshold_sqx2: 0.5 / (threshold * threshold),
+            inv_threshold_sqrt2_pi: INV_SQRT_OF_2PI / threshold,
+        }
+    }
+
+    /// `blurFactor` of the shaders for an offset of squared length `d2`.
+    fn blur(&self, d2: f32) -> f32 {
+        (-d2 * self.inv_sigma_qx2).exp() * self.inv_sigma_qx2_pi
+    }
+
+    fn delta(&self, qx2dc: f32, blur_factor: f32) -> f32 {
+        (-qx2dc * self.inv_threshold_sqx2).exp() * self.inv_threshold_sqrt2_pi * blur_factor
+    }
+}
+
+struct Kernel<'a> {
+    tex: &'a Texture,
+    /// Samples compared to find similar pixels
+    channels: usize,
+    /// Sample holding alpha when it's kept apart from the compared ones
+    alpha: Option<usize>,
+    alpha_mode: AlphaMode,
+    transfer_function: TransferFunction,
+    use_hsv: bool,
+    /// Space colour is filtered in, luma and chroma get their own falloff unless it's RGB
+    color_space: ColorSpace,
+    luma: Falloff,
+    chroma: Falloff,
+    radius: f32,
+    radial: RadialParams,
+    non_local_means: NonLocalMeansParams,
+    /// Guides divided by their thresholds, edges come from them instead of the input when there are any
+    guides: &'a [Texture],
+}
+
+impl Kernel<'_> {
+    fn diff(&self, walk: &Pixel, centr: &Pixel) -> Diff {
+        if self.use_hsv {
+            Diff::Hv(diff_hv(rgb_to_hv(walk), rgb_to_hv(centr)))
+        } else {
+            let mut d = [0.0; 4];
+            for c in 0..self.channels {
+                d[c] = walk[c] - centr[c];
+            }
+            Diff::Channels(d)
+        }
+    }
+
+    /// `fetchTexel` of the shaders, colour is decoded to linear light, premultiplied by alpha in [`AlphaMode::Premultiplied`] mode
+    /// and converted to the filter space.
+    fn fetch(&self, x: f32, y: f32) -> Pixel {
+        let mut px = self.tex.sample(x, y);
+        for v in px[..self.channels].iter_mut() {
+            *v = self.transfer_function.to_linear(*v);
+        }
+        if let (Some(a), AlphaMode::Premultiplied) = (self.alpha, self.alpha_mode) {
+            for c in 0..self.channels {
+                px[c] *= px[a];
+            }
+        }
+        if self.color_space != ColorSpace::Rgb {
+            let [x, y, z] = self.color_space.to_filter_space([px[0], px[1], px[2]]);
+            px[..3].copy_from_slice(&[x, y, z]);
+        }
+        px
+    }
+
+    /// `deltaFactor` of the shaders for every sample, `pw` as in [`powdot`](Self::powdot).
+    ///
+    /// Luma and chroma weights only depend on their own differences in luma/chroma spaces, alpha goes with luma.
+    fn delta_factors(&self, d2: f32, walk: &Pixel, centr: &Pixel, pw: [f32; 2]) -> Pixel {
+        let blur_factor = self.luma.blur(d2);
+        if self.color_space == ColorSpace::Rgb {
+            return [self.luma.delta(self.powdot(self.diff(walk, centr), pw), blur_factor); 4];
+        }
+        let luma = self.luma.delta((walk[0] - centr[0]).abs().powf(2.0), blur_factor);
+        let chroma_qx2dc = (walk[1] - centr[1]).abs().powf(2.0) + (walk[2] - centr[2]).abs().powf(2.0);
+        let chroma = self.chroma.delta(chroma_qx2dc, self.chroma.blur(d2));
+        [luma, chroma, chroma, luma]
+    }
+
+    fn weight(&self, delta_factors: Pixel, walk: &Pixel) -> Pixel {
+        match (self.alpha, self.alpha_mode) {
+            (Some(a), AlphaMode::UseAsWeight) => delta_factors.map(|f| f * walk[a]),
+            _ => delta_factors,
+        }
+    }
+
+    fn accumulate(&self, a_buff: &mut Pixel, z_buff: &mut Pixel, delta_factors: &Pixel, walk: &Pixel) {
+        for c in 0..4 {
+            z_buff[c] += delta_factors[c];
+        }
+        for c in 0..self.channels {
+            a_buff[c] += delta_factors[c] * walk[c];
+        }
+        if let Some(a) = self.alpha {
+            a_buff[a] += delta_factors[a] * walk[a];
+        }
+    }
+
+    /// Blends the weighted sum with the centre pixel by `fres`, resolves alpha according to `alpha_mode` and encodes colour back.
+    fn resolve(&self, mut a_buff: Pixel, mut z_buff: Pixel, centr: &Pixel, fres: f32) -> Pixel {
+        if z_buff[..self.channels].iter().any(|z| *z <= 0.0) {
+            //Every neighbour is outside in constant border mode or transparent when alpha is used as weight
+            a_buff = *centr;
+            z_buff = [1.0; 4];
+        }
+        let mut res = [0.0; 4];
+        for c in 0..self.channels {
+            //Chroma has its own threshold, the Radial blend only holds back luma
+            let fres = if c > 0 && self.color_space != ColorSpace::Rgb { 1.0 } else { fres };
+            res[c] = a_buff[c] * fres / z_buff[c] + centr[c] * (1.0 - fres);
+        }
+        if self.color_space != ColorSpace::Rgb {
+            let [r, g, b] = self.color_space.to_rgb([res[0], res[1], res[2]]);
+            res[..3].copy_from_slice(&[r, g, b]);
+        }
+        if let Some(a) = self.alpha {
+            let filtered_alpha = a_buff[a] * fres / z_buff[a] + centr[a] * (1.0 - fres);
+            if self.alpha_mode == AlphaMode::Premultiplied {
+                for v in res[..self.channels].iter_mut() {
+                    *v /= filtered_alpha.max(EPSILON);
+                }
+            }
+            res[a] = match self.alpha_mode {
+                AlphaMode::Denoise => filtered_alpha,
+                _ => centr[a],
+            };
+        }
+        for v in res[..self.channels].iter_mut() {
+            *v = self.transfer_function.from_linear(*v);
+        }
+        res
+    }
+
+    /// `powdot` of the shaders, `pw` is only used in HSV mode, channel differences are always squared.
+    fn powdot(&self, diff: Diff, pw: [f32; 2]) -> f32 {
+        match diff {
+            Diff::Channels(d) => d[..self.channels].iter().map(|v| v.abs().powf(2.0)).sum(),
+            Diff::Hv(d) => d[0].abs().powf(pw[0]) + d[1].abs().powf(pw[1]),
+        }
+    }
+
+    fn length(&self, diff: Diff) -> f32 {
+        match diff {
+            Diff::Channels(d) => d[..self.channels].iter().map(|v| v * v).sum::<f32>().sqrt(),
+            Diff::Hv(d) => (d[0] * d[0] + d[1] * d[1]).sqrt(),
+        }
+    }
+
+    /// `deltaFactor` of the guided Smart shader, the product of the range weights of the guides is one exponential.
+    fn guide_factor(&self, d2: f32, walk_guides: impl Iterator<Item = Pixel>, centr_guides: &[Pixel]) -> f32 {
+        let guide_diff: f32 = walk_guides.zip(centr_guides)
+            .map(|(walk, centr)| walk.iter().zip(centr).map(|(w, c)| (w - c) * (w - c)).sum::<f32>())
+            .sum();
+        (-guide_diff * 0.5).exp() * self.luma.blur(d2)
+    }
+
+    fn smart(&self, x: f32, y: f32) -> Pixel {
+        let radius = self.radius;
+        let rad_q = radius * radius;
+
+        let centr = self.fetch(x, y);
+        let centr_guides: Vec<Pixel> = self.guides.iter().map(|guide| guide.sample(x, y)).collect();
+
+        let mut z_buff = [0.0; 4];
+        let mut a_buff = [0.0; 4];
+
+        let mut dx = -radius;
+        while dx <= radius {
+            let pt = (rad_q - dx * dx).sqrt();
+            let mut dy = -pt;
+            while dy <= pt {
+                if !self.tex.is_counted(x + dx, y + dy) {
+                    dy += 1.0;
+                    continue;
+                }
+                let walk = self.fetch(x + dx, y + dy);
+                let delta_factors = match self.guides.is_empty() {
+                    true => self.delta_factors(dx * dx + dy * dy, &walk, &centr, [1.75, 1.5]),
+                    false => {
+                        let walk_guides = self.guides.iter().map(|guide| guide.sample(x + dx, y + dy));
+                        [self.guide_factor(dx * dx + dy * dy, walk_guides, &centr_guides); 4]
+                    }
+                };
+                let delta_factors = self.weight(delta_factors, &walk);
+
+                self.accumulate(&mut a_buff, &mut z_buff, &delta_factors, &walk);
+                dy += 1.0;
+            }
+            dx += 1.0;
+        }
+        self.resolve(a_buff, z_buff, &centr, 1.0)
+    }
+
+    fn radial(&self, x: f32, y: f32) -> Pixel {
+        let channels = self.channels;
+        let radius = self.radius;
+
+        let centr = self.fetch(x, y);
+
+        let perimeter = (2.0 * PI * radius).ceil();
+        let step = 1.0 / radius;
+
+        let mut min_diff = 9999999.0f32;
+        let mut max_diff = 0.0f32;
+        let mut best_i = 0.0f32;
+        let tuning = self.radial;
+        let pw = tuning.hsv_search_power;
+        let small_radius = tuning.small_radius;
+
+        let mut i = 0.0f32;
+        while i < perimeter {
+            let (sini, cosi) = (i * step).sin_cos();
+            let mut diffsum = 0.0;
+            let mut blursum = 0.0;
+            let mut dr = 1.0;
+            let mut r = 1.0f32;
+            while r < small_radius {
+                let walk = self.fetch(x + r * cosi, y + r * sini);
+                let qx2dc = self.powdot(self.diff(&walk, &centr), pw);
+                let blur_factor = 1.0 - r / small_radius;
+                diffsum += qx2dc * blur_factor;
+                blursum += blur_factor;
+                dr *= 1.25;
+                r += dr;
+            }
+            diffsum /= blursum;
+            if diffsum < min_diff {
+                best_i = i;
+            }
+            max_diff = max_diff.max(diffsum);
+            min_diff = min_diff.min(diffsum);
+            i += tuning.angle_step;
+        }
+
+        let mut best_disperse = 1.0f32;
+        min_diff = 9999999.0;
+        //Refines the direction between the coarse neighbours of the best one, the margin keeps the last one despite rounding
+        let mut i = best_i - tuning.angle_step;
+        while i < best_i + tuning.angle_step + 0.8 * tuning.refine_step {
+            let (sini, cosi) = (i * step).sin_cos();
+            let mut diffsum = 0.0;
+            let mut prev_walk = centr;
+            let mut disperse = 0.0;
+            let mut r = 1.0f32;
+            while r < radius {
+                let walk = self.fetch(x + r * cosi, y + r * sini);
+                disperse += self.length(self.diff(&walk, &prev_walk));
+                prev_walk = walk;
+                diffsum += self.powdot(self.diff(&walk, &centr), pw);
+                r += 1.0;
+            }
+            if diffsum < min_diff {
+                best_disperse = disperse;
+                best_i = i;
+            }
+            max_diff = max_diff.max(diffsum);
+            min_diff = min_diff.min(diffsum);
+            i += tuning.refine_step;
+        }
+
+        best_disperse = (best_disperse / radius).powf(tuning.disperse_exponent);
+
+        let max_possible_diff = small_radius * match (self.use_hsv, channels) {
+            (true, _) => 2.0,
+            (false, 1) => 1.0,
+            (false, _) => 3.0,
+        };
+        let fres = (min_diff / max_possible_diff).powf(tuning.fres_exponent).max(EPSILON);
+
+        let mut z_buff = [0.0; 4];
+        let mut a_buff = [0.0; 4];
+        let dp = ((perimeter / tuning.direction_divisor).round() as i32).max(1);
+        let dpd = dp * 2;
+        let mut i = best_i - dp as f32;
+        while i <= best_i + dp as f32 {
+            let (sini, cosi) = (i * step).sin_cos();
+            let maxr = 1 + ((radius * (dpd as f32 - (best_i - i).abs())) / dpd as f32) as i32;
+            for r in 1..maxr {
+                let (dx, dy) = (r as f32 * cosi, r as f32 * sini);
+                if !self.tex.is_counted(x + dx, y + dy) {
+                    continue;
+                }
+                let walk = self.fetch(x + dx, y + dy);
+                let delta_factors = self.delta_factors(dx * dx + dy * dy, &walk, &centr, tuning.hsv_filter_power);
+                //zero disperse - business as usual. Large disperse - have to smooth all with no regret.
+                let delta_factors = delta_factors.map(|f| f.powf(1.0 - best_disperse));
+                let delta_factors = self.weight(delta_factors, &walk);
+
+                self.accumulate(&mut a_buff, &mut z_buff, &delta_factors, &walk);
+            }
+            i += 1.0;
+        }
+
+        self.resolve(a_buff, z_buff, &centr, fres)
+    }
+
+    /// `sqDiff` of the non-local means shader, luma and chroma apart in luma/chroma spaces.
+    fn sq_diff(&self, a: &Pixel, b: &Pixel) -> [f32; 2] {
+        match (self.diff(a, b), self.color_space) {
+            (Diff::Hv(d), _) => [(d[0] * d[0] + d[1] * d[1]) / 2.0; 2],
+            (Diff::Channels(d), ColorSpace::Rgb) => [d[..self.channels].iter().map(|v| v * v).sum::<f32>() / self.channels as f32; 2],
+            (Diff::Channels(d), _) => [d[0] * d[0], (d[1] * d[1] + d[2] * d[2]) / 2.0],
+        }
+    }
+
+    fn non_local_means(&self, x: f32, y: f32) -> Pixel {
+        let patch_radius = self.non_local_means.patch_radius as i32;
+        let search_radius = self.non_local_means.search_radius as i32;
+        let inv_patch_area = 1.0 / ((2 * patch_radius + 1) * (2 * patch_radius + 1)) as f32;
+        let inv_strength_sq = 1.0 / (self.non_local_means.strength * self.non_local_means.strength);
+
+        //Offsets are whole pixels, so every texel the patches touch is fetched once
+        let reach = search_radius + patch_radius;
+        let side = 2 * reach + 1;
+        let window: Vec<Pixel> = (-reach..=reach)
+            .flat_map(|wy| (-reach..=reach).map(move |wx| (wx as f32, wy as f32)))
+            .map(|(wx, wy)| self.fetch(x + wx, y + wy))
+            .collect();
+        let at = |ox: i32, oy: i32| &window[((oy + reach) * side + ox + reach) as usize];
+        let centr = *at(0, 0);
+
+        let mut z_buff = [0.0; 4];
+        let mut a_buff = [0.0; 4];
+        for dy in -search_radius..=search_radius {
+            for dx in -search_radius..=search_radius {
+                if !self.tex.is_counted(x + dx as f32, y + dy as f32) {
+                    continue;
+                }
+                let walk = *at(dx, dy);
+                let mut patch_diff = [0.0; 2];
+                for py in -patch_radius..=patch_radius {
+                    for px in -patch_radius..=patch_radius {
+                        let [luma, chroma] = self.sq_diff(at(dx + px, dy + py), at(px, py));
+                        patch_diff[0] += luma;
+                        patch_diff[1] += chroma;
+                    }
+                }
+                let [luma, chroma] = patch_diff.map(|d| (-d * inv_patch_area * inv_strength_sq).exp());
+                let delta_factors = self.weight([luma, chroma, chroma, luma], &walk);
+
+                self.accumulate(&mut a_buff, &mut z_buff, &delta_factors, &walk);
+            }
+        }
+        self.resolve(a_buff, z_buff, &centr, 1.0)
+    }
+}
+
+/// Denoises `buf` holding `img_w` x `img_h` pixels of 1, 2, 3 or 4 interleaved channels without touching the GPU.
+///
+/// `use_hsv` only applies to colour images, grayscale ones are always processed as is. `guides` are the layers made by
+/// [`guide::layers`](crate::guide::layers), Smart takes its edges from them when given.
+pub(crate) fn denoise<D>(buf: &[D], img_w: u32, img_h: u32, params: DenoiseParams, use_hsv: bool, algo: Algo, guides: Option<&[Pixel]>)
+    -> Result<Vec<D>, DenoiseError>
+where D: Denoiseable
+{
+    let num_pixels = img_w as usize * img_h as usize;
+    if num_pixels == 0 || !buf.len().is_multiple_of(num_pixels) {
+        return Err(DenoiseError::BufferSizeMismatch { width: img_w, height: img_h, len: buf.len() });
+    }
+    let num_input_samples = buf.len() / num_pixels;
+    let params = params.resolve::<D>()?;
+    let white_level = params.white_level();
+    //Alpha is never compared, same as in the shaders
+    let (channels, alpha) = match num_input_samples {
+        1 => (1, None),
+        2 => (1, Some(1)),
+        3 => (3, None),
+        4 => (3, Some(3)),
+        channels => return Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: std::any::type_name::<D>() }),
+    };
+
+    let data = buf.chunks(num_input_samples)
+        .map(|px| {
+            let mut normalised = [0.0; 4];
+            for (n, v) in normalised.iter_mut().zip(px) {
+                let v: f32 = v.as_();
+                *n = v / white_level;
+            }
+            normalised
+        })
+        .collect();
+    let tex = Texture { width: img_w as usize, height: img_h as usize, samples: num_input_samples.max(channels),
+                        border_mode: params.border_mode(), data };
+    let color_space = params.filter_space(use_hsv, guides.is_some(), num_input_samples)?;
+    let guides: Vec<Texture> = guides.unwrap_or_default().chunks(num_pixels)
+        .map(|layer| Texture { width: img_w as usize, height: img_h as usize, samples: 4, border_mode: params.border_mode(),
+                               data: layer.to_vec() })
+        .collect();
+    let (chroma_sigma, chroma_threshold) = params.chroma();
+    let sigma = match color_space {
+        ColorSpace::Rgb => params.sigma,
+        _ => params.sigma.max(chroma_sigma),
+    };
+    let kernel = Kernel { tex: &tex, channels, alpha, alpha_mode: params.alpha_mode,
+                          transfer_function: params.transfer_function(), use_hsv: use_hsv && channels == 3, color_space,
+                          luma: Falloff::new(params.sigma, params.threshold), chroma: Falloff::new(chroma_sigma, chroma_threshold),
+                          radius: (params.kSigma * sigma).round(), radial: params.radial(),
+                          non_local_means: params.non_local_means(), guides: &guides };
+
+    let mut result = vec![D::zero(); buf.len()];
+    result.par_chunks_mut(img_w as usize * num_input_samples)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, out_px) in row.chunks_mut(num_input_samples).enumerate() {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let filtered = match algo {
+                    Algo::Smart => kernel.smart(px, py),
+                    Algo::Radial => kernel.radial(px, py),
+                    Algo::NonLocalMeans => kernel.non_local_means(px, py),
+                };
+                for (o, v) in out_px.iter_mut().zip(filtered) {
+                    *o = D::from_f32(v * white_level);
+                }
+            }
+        });
+    Ok(result)
+}
@@ -1,21 +1,20 @@
 use std::sync::Arc;
-use std::time::Instant;
-use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, SubpassContents};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, SubpassContents};
 use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
-use vulkano::device::{Device, Queue};
-use vulkano::image::{ImageAccess, ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::device::Device;
+use vulkano::image::{ImageAccess, StorageImage};
 use vulkano::image::view::ImageView;
 use vulkano::pipeline::{GraphicsPipeline, Pipeline, PipelineBindPoint};
 use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
 use vulkano::pipeline::graphics::vertex_input::BuffersDefinition;
 use vulkano::pipeline::graphics::viewport::{Viewport, ViewportState};
-use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, Subpass};
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass};
 use vulkano::sampler::Sampler;
-use vulkano::sync;
-use vulkano::sync::GpuFuture;
 use bytemuck::{Pod, Zeroable};
 use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, TypedBufferAccess};
-use crate::{Algo, DenoiseParams, ShaderParams, UsingShader};
+use vulkano::format::Format;
+use crate::{Algo, ColorSpace, DenoiseError, ShaderParams, UsingShader};
+use crate::denoiser::{guide_view, ImageBindings};
 
 
 #[repr(C)]
@@ -26,20 +25,28 @@ struct Vertex {
 
 vulkano::impl_vertex!(Vertex, position);
 
-pub(crate) fn denoise(device: Arc<Device>, queue: Arc<Queue>, input_img: Arc<StorageImage>, result_img: Arc<StorageImage>,
-                      sampler: Arc<Sampler>, denoise_params: DenoiseParams, use_hsv: bool, algo: Algo) {
-    let shader = crate::generated::get_denoise_shader(device.clone(), result_img.format(), UsingShader::Fragment, use_hsv, algo);
-    let vert_shader = crate::vertex_shader::load(device.clone()).unwrap();
+/// Graphics pipeline together with everything needed to reuse it for images of any size.
+pub(crate) struct FragmentPipeline {
+    render_pass: Arc<RenderPass>,
+    graphics_pipeline: Arc<GraphicsPipeline>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+}
 
-    let img_w = input_img.dimensions().width();
-    let img_h = input_img.dimensions().height();
+pub(crate) fn create_pipeline(device: Arc<Device>, format: Format, use_hsv: bool, color_space: ColorSpace, algo: Algo, guided: bool)
+    -> Result<FragmentPipeline, DenoiseError> {
+    let shader = crate::generated::get_denoise_shader(device.clone(), format, UsingShader::Fragment, use_hsv, color_space, algo, guided)?;
+    let vert_shader = crate::vertex_shader::load(device.clone())?;
+    let fragment_entry = shader.entry_point("main")
+        .ok_or_else(|| DenoiseError::PipelineCreation("no main entry point in fragment shader".to_string()))?;
+    let vertex_entry = vert_shader.entry_point("main")
+        .ok_or_else(|| DenoiseError::PipelineCreation("no main entry point in vertex shader".to_string()))?;
 
     let render_pass = vulkano::single_pass_renderpass!(device.clone(),
             attachments: {
                 color: {
                     load: Clear,
                     store: DontCare,
-                    format: result_img.format(),
+                    format: format,
                     samples: 1,
                 }
             },
@@ -47,56 +54,18 @@ pub(crate) fn denoise(device: Arc<Device>, queue: Arc<Queue>, input_img: Arc<Sto
                 color: [color],
                 depth_stencil: {}
             }
-        )
-        .unwrap();
-
-    let viewport = Viewport {
-        origin: [0.0, 0.0],
-        dimensions: [img_w as f32, img_h as f32],
-        depth_range: 0.0..1.0,
-    };
+        )?;
+    let subpass = Subpass::from(render_pass.clone(), 0)
+        .ok_or_else(|| DenoiseError::PipelineCreation("render pass has no subpass".to_string()))?;
 
     let graphics_pipeline = GraphicsPipeline::start()
-        .fragment_shader(shader.entry_point("main").unwrap(), ())
-        .vertex_shader(vert_shader.entry_point("main").unwrap(), ())
+        .fragment_shader(fragment_entry, ())
+        .vertex_shader(vertex_entry, ())
         .input_assembly_state(InputAssemblyState::new())
         .vertex_input_state(BuffersDefinition::new().vertex::<Vertex>())
-        .viewport_state(ViewportState::viewport_fixed_scissor_irrelevant([viewport.clone()]))
-        .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
-        .build(device.clone())
-        .unwrap();
-
-    let input_view = ImageView::new_default(input_img).unwrap();
-    let output_view = ImageView::new_default(result_img).unwrap();
-
-    let layout = graphics_pipeline.layout().set_layouts().get(0).unwrap();
-
-    let items = match algo {
-        Algo::Smart => vec![WriteDescriptorSet::image_view_sampler(0, input_view, sampler)],
-        Algo::Radial => {
-            let inter_res_img = StorageImage::with_usage(device.clone(),
-                                                         ImageDimensions::Dim2d { width: img_w, height: img_h, array_layers: 1},
-                                                         vulkano::format::Format::R32_SFLOAT,
-                                                         ImageUsage {
-                                                             transfer_source: true,
-                                                             transfer_destination: false,
-                                                             sampled: false,
-                                                             storage: true,
-                                                             color_attachment: true,
-                                                             depth_stencil_attachment: false,
-                                                             transient_attachment: false,
-                                                             input_attachment: false
-                                                         },
-                                                         ImageCreateFlags::none(),
-                                                         Some(queue.family())).unwrap();
-            vec![WriteDescriptorSet::image_view_sampler(0, input_view, sampler)]/*,
-                 WriteDescriptorSet::image_view(1, ImageView::new_default(inter_res_img).unwrap())]*/
-        }
-    };
-
-    let set = PersistentDescriptorSet::new(layout.clone(), items).unwrap();
-
-    let push_constants = ShaderParams::new(img_w, img_h, denoise_params);
+        .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+        .render_pass(subpass)
+        .build(device.clone())?;
 
     let vertices = [
         Vertex {
@@ -119,54 +88,70 @@ pub(crate) fn denoise(device: Arc<Device>, queue: Arc<Queue>, input_img: Arc<Sto
         },
     ];
     let vertex_buffer = CpuAccessibleBuffer::<[Vertex]>::from_iter(
-        device.clone(),
+        device,
         BufferUsage::all(),
         false,
         vertices,
-    )
-        .unwrap();
+    )?;
+
+    Ok(FragmentPipeline { render_pass, graphics_pipeline, vertex_buffer })
+}
+
+/// Descriptor set binding `input_img` and `guide_img` through `sampler` and a framebuffer rendering into `result_img`.
+pub(crate) fn bind(pipeline: &FragmentPipeline, input_img: Arc<StorageImage>, result_img: Arc<StorageImage>,
+                   guide_img: Option<Arc<StorageImage>>, sampler: Arc<Sampler>) -> Result<ImageBindings, DenoiseError> {
+    let input_view = ImageView::new_default(input_img.clone())?;
+    let output_view = ImageView::new_default(result_img.clone())?;
 
+    let layout = pipeline.graphics_pipeline.layout().set_layouts().get(0)
+        .ok_or_else(|| DenoiseError::PipelineCreation("fragment shader has no descriptor set".to_string()))?;
+
+    let mut items = vec![WriteDescriptorSet::image_view_sampler(0, input_view, sampler.clone())];
+    if let Some(guide_img) = guide_img.clone() {
+        items.push(WriteDescriptorSet::image_view_sampler(1, guide_view(guide_img)?, sampler));
+    }
+
+    let set = PersistentDescriptorSet::new(layout.clone(), items)?;
 
     let framebuffer = Framebuffer::new(
-        render_pass,
+        pipeline.render_pass.clone(),
         FramebufferCreateInfo {
             attachments: vec![output_view],
             ..Default::default()
         },
-    ).unwrap();
-
-    //Computation itself
-    let now = Instant::now();
-    let command_buffer = {
-        let mut builder =
-            AutoCommandBufferBuilder::primary(device.clone(), queue.family(), CommandBufferUsage::OneTimeSubmit).unwrap();
-        builder
-            .begin_render_pass(
-                framebuffer,
-                SubpassContents::Inline,
-                vec![[0u32, 0u32].into()],
-            )
-            .unwrap()
-            //.bind_pipeline_compute(compute_pipeline.clone())
-            .set_viewport(0, [viewport.clone()])
-            .bind_pipeline_graphics(graphics_pipeline.clone())
-            .bind_descriptor_sets(PipelineBindPoint::Graphics, graphics_pipeline.layout().clone(), 0, set.clone())
-            .bind_vertex_buffers(0, vertex_buffer.clone())
-            .push_constants(graphics_pipeline.layout().clone(), 0, push_constants)
-            .draw(vertex_buffer.len() as u32, 1, 0, 0)
-            .unwrap()
-            .end_render_pass()
-            //.dispatch([img_w, img_h, 1])
-            .unwrap();
-        builder.build().unwrap()
-    };
+    )?;
+    Ok(ImageBindings { input_img, result_img, guide_img, set, framebuffer: Some(framebuffer) })
+}
+
+/// Records the render pass filtering `bindings.input_img` into `bindings.result_img`.
+pub(crate) fn record(builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, pipeline: &FragmentPipeline,
+                     bindings: &ImageBindings, push_constants: ShaderParams) -> Result<(), DenoiseError> {
+    let graphics_pipeline = pipeline.graphics_pipeline.clone();
+    let framebuffer = bindings.framebuffer.clone()
+        .ok_or_else(|| DenoiseError::PipelineCreation("fragment pass needs a framebuffer".to_string()))?;
+
+    //Output may be a tile of the input
+    let img_w = bindings.result_img.dimensions().width();
+    let img_h = bindings.result_img.dimensions().height();
 
+    let viewport = Viewport {
+        origin: [0.0, 0.0],
+        dimensions: [img_w as f32, img_h as f32],
+        depth_range: 0.0..1.0,
+    };
 
-    let future = sync::now(device.clone())
-        .then_execute(queue, command_buffer)
-        .unwrap()
-        .then_signal_fence_and_flush()
-        .unwrap();
-    future.wait(None).unwrap();
-    #[cfg(debug_assertions)] eprintln!("Execute computation itself taken {} milliseconds", now.elapsed().as_millis());
-}
\ No newline at end of file
+    builder
+        .begin_render_pass(
+            framebuffer,
+            SubpassContents::Inline,
+            vec![[0u32, 0u32].into()],
+        )?
+        .set_viewport(0, [viewport])
+        .bind_pipeline_graphics(graphics_pipeline.clone())
+        .bind_descriptor_sets(PipelineBindPoint::Graphics, graphics_pipeline.layout().clone(), 0, bindings.set.clone())
+        .bind_vertex_buffers(0, pipeline.vertex_buffer.clone())
+        .push_constants(graphics_pipeline.layout().clone(), 0, push_constants)
+        .draw(pipeline.vertex_buffer.len() as u32, 1, 0, 0)?
+        .end_render_pass()?;
+    Ok(())
+}
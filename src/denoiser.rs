@@ -0,0 +1,878 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::mpsc::{self, SendError, Sender};
+use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferExecFuture, CommandBufferUsage, PrimaryAutoCommandBuffer, PrimaryCommandBuffer};
+use vulkano::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
+use vulkano::image::view::{ImageView, ImageViewCreateInfo, ImageViewType};
+use vulkano::pipeline::ComputePipeline;
+use vulkano::render_pass::Framebuffer;
+use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, SamplerReductionMode};
+use vulkano::sync::{FenceSignalFuture, GpuFuture, NowFuture};
+use clap::ValueEnum;
+use crate::{denoise_compute, denoise_cpu, denoise_frag, guide, vlk_init_with, Algo, BorderMode, ColorSpace, DeviceSelector, DenoiseError, DenoiseParams,
+            Denoiseable, Guide, ShaderParams, UsingShader};
+use crate::denoise_frag::FragmentPipeline;
+use crate::handle::{self, DenoiseHandle};
+
+/// Format, shader type, HSV, colour space, algorithm and whether edges come from guides
+type PipelineKey = (Format, UsingShader, bool, ColorSpace, Algo, bool);
+type ImageKey = (u32, u32, Format);
+/// Pipeline, border mode, input and result image and number of guides the bindings were made for
+type BindingKey = (PipelineKey, BorderMode, ImageKey, ImageKey, u32);
+type WaitJob = Box<dyn FnOnce() + Send>;
+type TileFence = FenceSignalFuture<CommandBufferExecFuture<NowFuture, PrimaryAutoCommandBuffer>>;
+
+#[derive(Clone)]
+enum DenoisePipeline {
+    Compute(Arc<ComputePipeline>),
+    Fragment(Arc<FragmentPipeline>),
+}
+
+/// Format guides are uploaded in, one array layer per guide
+const GUIDE_FORMAT: Format = Format::R32G32B32A32_SFLOAT;
+
+/// Staging buffer plus image per input pixel, for the input and the guides. Input is uploaded as `f32` whatever the format.
+fn input_px_bytes(sampled_format: Format, guide_layers: u32) -> u64 {
+    let channels = sampled_format.components().iter().filter(|bits| **bits > 0).count() as u64;
+    let staging = channels * std::mem::size_of::<f32>() as u64;
+    staging + sampled_format.block_size().unwrap_or(16) + 2 * guide_layers as u64 * GUIDE_FORMAT.block_size().unwrap_or(16)
+}
+
+/// Staging buffer plus image per result pixel, the result is read back in its own format.
+fn result_px_bytes(result_format: Format) -> u64 {
+    2 * result_format.block_size().unwrap_or(16)
+}
+
+/// Device memory of the images made for `key`.
+fn binding_bytes(key: &BindingKey) -> u64 {
+    let (_, _, (input_w, input_h, sampled_format), (result_w, result_h, result_format), guide_layers) = *key;
+    let input_px = sampled_format.block_size().unwrap_or(16) + guide_layers as u64 * GUIDE_FORMAT.block_size().unwrap_or(16);
+    input_px * input_w as u64 * input_h as u64 + result_format.block_size().unwrap_or(16) * result_w as u64 * result_h as u64
+}
+
+/// Input and result image with the descriptor set, and for the fragment path the framebuffer, that use them.
+pub(crate) struct ImageBindings {
+    pub(crate) input_img: Arc<StorageImage>,
+    pub(crate) result_img: Arc<StorageImage>,
+    /// Guides of [`Denoiser::denoise_guided`], same size as the input
+    pub(crate) guide_img: Option<Arc<StorageImage>>,
+    pub(crate) set: Arc<PersistentDescriptorSet>,
+    pub(crate) framebuffer: Option<Arc<Framebuffer>>,
+}
+
+/// View of every layer of `guide_img`, arrayed even for a single guide as `sampler2DArray` expects.
+pub(crate) fn guide_view(guide_img: Arc<StorageImage>) -> Result<Arc<ImageView<StorageImage>>, DenoiseError> {
+    let create_info = ImageViewCreateInfo { view_type: ImageViewType::Dim2dArray, ..ImageViewCreateInfo::from_image(&guide_img) };
+    Ok(ImageView::new(guide_img, create_info)?)
+}
+
+/// Released resource with its size and when it was given back.
+struct PoolItem<K, T> {
+    key: K,
+    item: T,
+    bytes: u64,
+    stamp: u64,
+}
+
+/// Keeps released resources so later calls with the same key don't have to allocate them again.
+///
+/// Items stay in the order they were given back, so [`Pool::evict_oldest`] drops the least recently used one.
+struct Pool<K, T> {
+    free: Mutex<VecDeque<PoolItem<K, T>>>,
+}
+
+impl<K: Eq, T> Pool<K, T> {
+    fn new() -> Self {
+        Self { free: Mutex::new(VecDeque::new()) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<PoolItem<K, T>>> {
+        self.free.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn take(&self, key: &K) -> Option<T> {
+        let mut free = self.lock();
+        let pos = free.iter().rposition(|entry| entry.key == *key)?;
+        free.remove(pos).map(|entry| entry.item)
+    }
+
+    fn give_back(&self, key: K, item: T, bytes: u64, stamp: u64) {
+        self.lock().push_back(PoolItem { key, item, bytes, stamp });
+    }
+
+    fn bytes(&self) -> u64 {
+        self.lock().iter().map(|entry| entry.bytes).sum()
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        self.lock().front().map(|entry| entry.stamp)
+    }
+
+    fn evict_oldest(&self) {
+        self.lock().pop_front();
+    }
+}
+
+/// Vulkan device and queue with cached pipelines and pooled staging buffers and images.
+struct GpuContext {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    /// One per border mode, `Constant` shares the clamping one and skips outside samples in the shader
+    samplers: HashMap<BorderMode, Arc<Sampler>>,
+    pipelines: Mutex<HashMap<PipelineKey, DenoisePipeline>>,
+    input_buffers: Pool<usize, Arc<CpuAccessibleBuffer<[f32]>>>,
+    result_buffers: Pool<usize, Arc<CpuAccessibleBuffer<[u8]>>>,
+    bindings: Pool<BindingKey, ImageBindings>,
+    /// Orders resources given back to any of the pools
+    pool_clock: AtomicU64,
+    /// Bytes of staging buffers and images a single pass may use, larger images are split in tiles,
+    /// the pools are trimmed to it too
+    memory_budget: AtomicU64,
+    /// Most bytes [`PendingTile::memory_bytes`] counts for the tiles of one image in flight at once
+    peak_image_memory: AtomicU64,
+    /// Thread waiting for fences of asynchronous calls, started on first use
+    waiter: Mutex<Option<Sender<WaitJob>>>,
+}
+
+impl GpuContext {
+    fn new(device: Arc<Device>, queue: Arc<Queue>) -> Result<Self, DenoiseError> {
+        let mut samplers = HashMap::new();
+        for border_mode in BorderMode::value_variants() {
+            let address_mode = match border_mode {
+                BorderMode::Clamp | BorderMode::Constant => SamplerAddressMode::ClampToEdge,
+                BorderMode::Mirror => SamplerAddressMode::MirroredRepeat,
+                BorderMode::Wrap => SamplerAddressMode::Repeat,
+            };
+            let sampler = Sampler::new(device.clone(), SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                mipmap_mode: SamplerMipmapMode::Linear,
+                reduction_mode: SamplerReductionMode::WeightedAverage,
+                address_mode: [address_mode; 3],
+                mip_lod_bias: 0.0,
+                lod: 0.0..=0.0,
+                anisotropy: None,
+                //anisotropy: Some(4.0),
+                ..Default::default()
+            })?;
+            samplers.insert(*border_mode, sampler);
+        }
+        let memory_budget = device.physical_device().memory_heaps()
+            .filter(|heap| heap.is_device_local())
+            .map(|heap| heap.size())
+            .max()
+            .unwrap_or(0) / 2;
+
+        Ok(Self {
+            device,
+            queue,
+            samplers,
+            pipelines: Mutex::new(HashMap::new()),
+            input_buffers: Pool::new(),
+            result_buffers: Pool::new(),
+            bindings: Pool::new(),
+            pool_clock: AtomicU64::new(0),
+            memory_budget: AtomicU64::new(memory_budget),
+            peak_image_memory: AtomicU64::new(0),
+            waiter: Mutex::new(None),
+        })
+    }
+
+    fn pipeline(&self, key: PipelineKey) -> Result<DenoisePipeline, DenoiseError> {
+        let (format, shader_type, use_hsv, color_space, algo, guided) = key;
+        if let Some(pipeline) = self.pipelines.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+            return Ok(pipeline.clone());
+        }
+        //Built without holding the lock, pipeline creation may take a while
+        let pipeline = match shader_type {
+            UsingShader::Fragment => DenoisePipeline::Fragment(Arc::new(denoise_frag::create_pipeline(self.device.clone(), format, use_hsv, color_space, algo, guided)?)),
+            UsingShader::Compute => DenoisePipeline::Compute(denoise_compute::create_pipeline(self.device.clone(), format, use_hsv, color_space, algo, guided)?),
+            UsingShader::Cpu => return Err(DenoiseError::PipelineCreation("CPU backend has no pipeline".to_string())),
+        };
+        Ok(self.pipelines.lock().unwrap_or_else(|e| e.into_inner()).entry(key).or_insert(pipeline).clone())
+    }
+
+    fn input_buffer(&self, data: Vec<f32>) -> Result<Arc<CpuAccessibleBuffer<[f32]>>, DenoiseError> {
+        if let Some(buffer) = self.input_buffers.take(&data.len()) {
+            buffer.write()?.copy_from_slice(&data);
+            return Ok(buffer);
+        }
+        let input_usage = BufferUsage{
+            transfer_source: true,
+            transfer_destination: false,
+            uniform_texel_buffer: false,
+            storage_texel_buffer: false,
+            uniform_buffer: false,
+            storage_buffer: true,
+            index_buffer: false,
+            vertex_buffer: false,
+            indirect_buffer: false,
+            device_address: true,
+            _ne: Default::default()
+        };
+        Ok(CpuAccessibleBuffer::from_iter(self.device.clone(), input_usage, false,
+                                          data.into_iter())?)
+    }
+
+    fn result_buffer(&self, len: usize) -> Result<Arc<CpuAccessibleBuffer<[u8]>>, DenoiseError> {
+        if let Some(buffer) = self.result_buffers.take(&len) {
+            return Ok(buffer);
+        }
+        Ok(CpuAccessibleBuffer::from_iter(self.device.clone(), BufferUsage{
+            transfer_source: false,
+            transfer_destination: true,
+            uniform_texel_buffer: false,
+            storage_texel_buffer: false,
+            uniform_buffer: true,
+            storage_buffer: true,
+            index_buffer: false,
+            vertex_buffer: false,
+            indirect_buffer: false,
+            device_address: true,
+            _ne: Default::default()
+        }, false, (0..len).map(|_| 0u8))?)
+    }
+
+    fn input_image(&self, key: ImageKey) -> Result<Arc<StorageImage>, DenoiseError> {
+        let (width, height, format) = key;
+        Ok(StorageImage::with_usage(self.device.clone(),
+                                 ImageDimensions::Dim2d { width, height, array_layers: 1},
+                                 format,
+                                 ImageUsage {
+                                     transfer_source: false,
+                                     transfer_destination: true,
+                                     sampled: true,
+                                     storage: true,
+                                     color_attachment: false,
+                                     depth_stencil_attachment: false,
+                                     transient_attachment: false,
+                                     input_attachment: false
+                                 },
+                                 ImageCreateFlags::none(),
+                                 Some(self.queue.family()))?)
+    }
+
+    fn guide_image(&self, width: u32, height: u32, layers: u32) -> Result<Arc<StorageImage>, DenoiseError> {
+        Ok(StorageImage::with_usage(self.device.clone(),
+                                 ImageDimensions::Dim2d { width, height, array_layers: layers },
+                                 GUIDE_FORMAT,
+                                 ImageUsage {
+                                     transfer_source: false,
+                                     transfer_destination: true,
+                                     sampled: true,
+                                     storage: false,
+                                     color_attachment: false,
+                                     depth_stencil_attachment: false,
+                                     transient_attachment: false,
+                                     input_attachment: false
+                                 },
+                                 ImageCreateFlags::none(),
+                                 Some(self.queue.family()))?)
+    }
+
+    fn result_image(&self, key: ImageKey) -> Result<Arc<StorageImage>, DenoiseError> {
+        let (width, height, format) = key;
+        Ok(StorageImage::with_usage(self.device.clone(),
+                                 ImageDimensions::Dim2d { width, height, array_layers: 1},
+                                 format,
+                                 ImageUsage {
+                                     transfer_source: true,
+                                     transfer_destination: false,
+                                     sampled: false,
+                                     storage: true,
+                                     color_attachment: true,
+                                     depth_stencil_attachment: false,
+                                     transient_attachment: false,
+                                     input_attachment: false
+                                 },
+                                 ImageCreateFlags::none(),
+                                 Some(self.queue.family()))?)
+    }
+
+    /// Largest square output tile whose input and guides, with `halo` pixels on every side, fit the device limits and the budget.
+    fn max_tile_size(&self, halo: u32, sampled_format: Format, result_format: Format, guide_layers: u32) -> Result<u32, DenoiseError> {
+        let properties = self.device.physical_device().properties();
+        //Result images are color attachments too
+        let max_dimension = properties.max_image_dimension2_d
+            .min(properties.max_framebuffer_width)
+            .min(properties.max_framebuffer_height);
+        if 2 * halo >= max_dimension {
+            return Err(DenoiseError::InvalidParameter(format!("filter radius needs {} pixels of overlap, device allows images of {} pixels",
+                                                              halo, max_dimension)));
+        }
+        let (input_px, result_px) = (input_px_bytes(sampled_format, guide_layers), result_px_bytes(result_format));
+        let memory_budget = self.memory_budget.load(Ordering::Relaxed);
+        let by_memory = ((memory_budget / (input_px + result_px)) as f64).sqrt() as u32;
+        let tile = by_memory.saturating_sub(2 * halo).min(max_dimension - 2 * halo);
+        if tile == 0 {
+            return Err(DenoiseError::InvalidParameter(format!("memory budget of {} bytes can't hold a tile with {} pixels of overlap",
+                                                              memory_budget, halo)));
+        }
+        Ok(tile)
+    }
+
+    fn fits_single_pass(&self, img_w: u32, img_h: u32, sampled_format: Format, result_format: Format, guide_layers: u32) -> bool {
+        let properties = self.device.physical_device().properties();
+        let max_dimension = properties.max_image_dimension2_d
+            .min(properties.max_framebuffer_width)
+            .min(properties.max_framebuffer_height);
+        let bytes = (input_px_bytes(sampled_format, guide_layers) + result_px_bytes(result_format)) * img_w as u64 * img_h as u64;
+        img_w <= max_dimension && img_h <= max_dimension && bytes <= self.memory_budget.load(Ordering::Relaxed)
+    }
+
+    /// Images and their descriptor set for `key`, created on first use.
+    fn bindings(&self, key: BindingKey, pipeline: &DenoisePipeline) -> Result<ImageBindings, DenoiseError> {
+        if let Some(bindings) = self.bindings.take(&key) {
+            return Ok(bindings);
+        }
+        let ((_, _, _, _, algo, _), border_mode, input_key, result_key, guide_layers) = key;
+        let input_img = self.input_image(input_key)?;
+        let result_img = self.result_image(result_key)?;
+        let guide_img = match guide_layers {
+            0 => None,
+            layers => Some(self.guide_image(input_key.0, input_key.1, layers)?),
+        };
+        let sampler = self.samplers[&border_mode].clone();
+        match pipeline {
+            DenoisePipeline::Fragment(pipeline) => denoise_frag::bind(pipeline, input_img, result_img, guide_img, sampler),
+            DenoisePipeline::Compute(pipeline) => denoise_compute::bind(pipeline, input_img, result_img, guide_img, sampler, algo),
+        }
+    }
+
+    /// Gives the buffers and images of a finished tile back to the pools, then drops the least recently used
+    /// pooled resources until all pools together fit in the memory budget.
+    fn release(&self, tile: PendingTile) {
+        let stamp = self.pool_clock.fetch_add(1, Ordering::Relaxed);
+        let f32_size = std::mem::size_of::<f32>() as u64;
+        self.input_buffers.give_back(tile.input_len, tile.input_buf, tile.input_len as u64 * f32_size, stamp);
+        if let Some(guide_buf) = tile.guide_buf {
+            self.input_buffers.give_back(tile.guide_len, guide_buf, tile.guide_len as u64 * f32_size, stamp);
+        }
+        self.result_buffers.give_back(tile.result_len, tile.result_buf, tile.result_len as u64, stamp);
+        self.bindings.give_back(tile.binding_key, tile.bindings, binding_bytes(&tile.binding_key), stamp);
+
+        let memory_budget = self.memory_budget.load(Ordering::Relaxed);
+        while self.input_buffers.bytes() + self.result_buffers.bytes() + self.bindings.bytes() > memory_budget {
+            let oldest = [self.input_buffers.oldest(), self.result_buffers.oldest(), self.bindings.oldest()];
+            match oldest.iter().enumerate().filter_map(|(n, stamp)| stamp.map(|stamp| (stamp, n))).min() {
+                Some((_, 0)) => self.input_buffers.evict_oldest(),
+                Some((_, 1)) => self.result_buffers.evict_oldest(),
+                Some(_) => self.bindings.evict_oldest(),
+                None => break,
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn denoise<D>(&self, buf: &[D], img_w: u32, img_h: u32, shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo) -> Result<Vec<D>, DenoiseError>
+    where D: Denoiseable
+    {
+        self.submit(buf, img_w, img_h, shader_type, params, use_hsv, algo, None)?.wait(self)
+    }
+
+    /// Runs `job` on the waiter thread. Jobs run in submission order, which on a single queue is also the order fences signal.
+    fn wait_in_background(&self, job: WaitJob) -> Result<(), DenoiseError> {
+        let mut waiter = self.waiter.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(sender) = waiter.as_ref() {
+            match sender.send(job) {
+                Ok(()) => return Ok(()),
+                //Waiter thread is gone, start a new one
+                Err(SendError(job)) => return Self::start_waiter(&mut waiter, job),
+            }
+        }
+        Self::start_waiter(&mut waiter, job)
+    }
+
+    fn start_waiter(waiter: &mut Option<Sender<WaitJob>>, job: WaitJob) -> Result<(), DenoiseError> {
+        let (sender, receiver) = mpsc::channel::<WaitJob>();
+        thread::Builder::new()
+            .name("denoise-waiter".to_string())
+            .spawn(move || receiver.into_iter().for_each(|job| job()))
+            .map_err(|_| DenoiseError::OutOfHostMemory)?;
+        //Receiver lives as long as the thread, sending right after spawning can't fail
+        let _ = sender.send(job);
+        *waiter = Some(sender);
+        Ok(())
+    }
+
+    /// Submits every tile of the image and returns without waiting for the GPU.
+    ///
+    /// `guides` are the layers made by [`guide::layers`], edges are taken from them instead of the input when given.
+    #[allow(clippy::too_many_arguments)]
+    fn submit<D>(&self, buf: &[D], img_w: u32, img_h: u32, shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo,
+                 guides: Option<&[[f32; 4]]>) -> Result<PendingImage<D>, DenoiseError>
+    where D: Denoiseable
+    {
+        let num_pixels = img_w as usize * img_h as usize;
+        if num_pixels == 0 || !buf.len().is_multiple_of(num_pixels) {
+            return Err(DenoiseError::BufferSizeMismatch { width: img_w, height: img_h, len: buf.len() });
+        }
+        let num_input_samples = buf.len() / num_pixels;
+        //Hue needs colour, grayscale is filtered as is like on the CPU and there are no HSV shaders for it
+        let use_hsv = use_hsv && num_input_samples >= 3;
+        let params = params.resolve::<D>()?;
+
+        let num_output_samples = match num_input_samples {
+            3 => 4,
+            _ => num_input_samples
+        };
+
+        let sampled_format = D::type2sampled_format(num_input_samples)?;
+        let result_format = D::type2result_format(num_output_samples)?;
+        let color_space = params.filter_space(use_hsv, guides.is_some(), num_input_samples)?;
+        let pipeline_key = (result_format, shader_type, use_hsv, color_space, algo, guides.is_some());
+        let guide_layers = guides.map_or(0, |guides| (guides.len() / num_pixels) as u32);
+        let guide_input: Option<Vec<f32>> = guides.map(|guides| bytemuck::cast_slice(guides).to_vec());
+        let pipeline = self.pipeline(pipeline_key)?;
+
+        let input2sample: Vec<f32> = match num_input_samples {
+            3 => buf.iter()
+                    .enumerate()
+                    .flat_map(|(i, x)| {
+                        let rs: f32 = (*x).as_();
+                        if i%3==2 {vec![rs, params.white_level()]} //Adds opaque alpha channel for each 3rd item
+                        else {vec![rs]}
+                    })
+                    .collect(),
+            _ => buf.iter()
+                    .map(|x| {
+                        let rs: f32 = (*x).as_();
+                        rs
+                    })
+                    .collect()
+        };
+
+        let (finished, tiles) = if self.fits_single_pass(img_w, img_h, sampled_format, result_format, guide_layers) {
+            let binding_key = (pipeline_key, params.border_mode(), (img_w, img_h, sampled_format), (img_w, img_h, result_format), guide_layers);
+            let tile = self.submit_tile(&pipeline, binding_key, input2sample, guide_input, ShaderParams::new(img_w, img_h, params), [0, 0])?;
+            self.peak_image_memory.fetch_max(tile.memory_bytes(), Ordering::Relaxed);
+            (Vec::new(), vec![tile])
+        } else {
+            self.submit_tiled(&pipeline, pipeline_key, &input2sample, guide_input.as_deref(), img_w, img_h, num_output_samples, sampled_format,
+                              result_format, params)?
+        };
+
+        Ok(PendingImage { finished, tiles, img_w, img_h, num_input_samples, num_output_samples, sample: PhantomData })
+    }
+
+    /// Splits the image in tiles overlapping by the filter reach, so the stitched result equals a single pass.
+    ///
+    /// Tiles get their overlap from the border mode addressing wherever they reach past the image edge,
+    /// same as the sampler would do for the whole image. Guides are cut the same way.
+    ///
+    /// Once the tiles in flight hold more than the memory budget the oldest is waited on, so the next tile reuses
+    /// its buffers and images. Returns the tiles finished that way and the ones still in flight.
+    #[allow(clippy::too_many_arguments)]
+    fn submit_tiled(&self, pipeline: &DenoisePipeline, pipeline_key: PipelineKey, input: &[f32], guides: Option<&[f32]>, img_w: u32, img_h: u32,
+                    num_samples: usize, sampled_format: Format, result_format: Format, params: DenoiseParams)
+        -> Result<(Vec<FinishedTile>, Vec<PendingTile>), DenoiseError> {
+        let (_, _, _, _, algo, _) = pipeline_key;
+        let halo = params.halo(algo);
+        let guide_len = img_w as usize * img_h as usize * 4;
+        let guide_layers = guides.map_or(0, |guides| (guides.len() / guide_len) as u32);
+        let tile = self.max_tile_size(halo, sampled_format, result_format, guide_layers)?;
+        #[cfg(debug_assertions)] eprintln!("Denoising {}x{} in tiles of {} pixels with {} pixels of overlap", img_w, img_h, tile, halo);
+
+        let memory_budget = self.memory_budget.load(Ordering::Relaxed);
+        let mut finished = Vec::new();
+        let mut in_flight = VecDeque::new();
+        let mut in_flight_bytes = 0;
+        for tile_y in (0..img_h).step_by(tile as usize) {
+            for tile_x in (0..img_w).step_by(tile as usize) {
+                let tile_w = tile.min(img_w - tile_x);
+                let tile_h = tile.min(img_h - tile_y);
+                let (input_w, input_h) = (tile_w + 2 * halo, tile_h + 2 * halo);
+
+                let crop = |input: &[f32], num_samples: usize| {
+                    let mut tile_input = Vec::with_capacity(input_w as usize * input_h as usize * num_samples);
+                    for y in 0..input_h {
+                        let src_y = denoise_cpu::address(tile_y as isize + y as isize - halo as isize, img_h as usize, params.border_mode());
+                        for x in 0..input_w {
+                            let src_x = denoise_cpu::address(tile_x as isize + x as isize - halo as isize, img_w as usize, params.border_mode());
+                            let src = (src_y * img_w as usize + src_x) * num_samples;
+                            tile_input.extend_from_slice(&input[src..src + num_samples]);
+                        }
+                    }
+                    tile_input
+                };
+                let tile_input = crop(input, num_samples);
+                let tile_guides = guides.map(|guides| guides.chunks(guide_len).flat_map(|layer| crop(layer, 4)).collect());
+
+                let origin = [halo as f32 - tile_x as f32, halo as f32 - tile_y as f32];
+                let push_constants = ShaderParams::new(tile_w, tile_h, params)
+                    .with_tile([halo, halo], [origin[0], origin[1], origin[0] + img_w as f32, origin[1] + img_h as f32]);
+                let binding_key = (pipeline_key, params.border_mode(), (input_w, input_h, sampled_format), (tile_w, tile_h, result_format),
+                                   guide_layers);
+                let pending = self.submit_tile(pipeline, binding_key, tile_input, tile_guides, push_constants, [tile_x, tile_y])?;
+                in_flight_bytes += pending.memory_bytes();
+                in_flight.push_back(pending);
+                self.peak_image_memory.fetch_max(in_flight_bytes, Ordering::Relaxed);
+                //The newest tile stays in flight, it's the one overlapping with the wait
+                while in_flight_bytes > memory_budget && in_flight.len() > 1 {
+                    let oldest = in_flight.pop_front().unwrap();
+                    in_flight_bytes -= oldest.memory_bytes();
+                    finished.push(oldest.finish(self)?);
+                }
+            }
+        }
+        Ok((finished, in_flight.into()))
+    }
+
+    /// Uploads `input` and `guides`, filters it and reads the result back in one command buffer.
+    fn submit_tile(&self, pipeline: &DenoisePipeline, binding_key: BindingKey, input: Vec<f32>, guides: Option<Vec<f32>>,
+                   push_constants: ShaderParams, origin: [u32; 2]) -> Result<PendingTile, DenoiseError> {
+        let input_len = input.len();
+        let input_buf = self.input_buffer(input)?;
+        let guide_len = guides.as_ref().map_or(0, Vec::len);
+        let guide_buf = guides.map(|guides| self.input_buffer(guides)).transpose()?;
+        let bindings = self.bindings(binding_key, pipeline)?;
+
+        let (_, _, (input_w, input_h, _), (result_w, result_h, result_format), guide_layers) = binding_key;
+        let result_len = result_w as usize * result_h as usize * result_format.block_size().unwrap_or(0) as usize;
+        let result_buf = self.result_buffer(result_len)?;
+
+        let mut builder =
+            AutoCommandBufferBuilder::primary(self.device.clone(), self.queue.family(), CommandBufferUsage::OneTimeSubmit)?;
+        builder
+            .copy_buffer_to_image(input_buf.clone(), bindings.input_img.clone())?;
+        if let (Some(guide_buf), Some(guide_img)) = (&guide_buf, &bindings.guide_img) {
+            builder.copy_buffer_to_image_dimensions(guide_buf.clone(), guide_img.clone(), [0, 0, 0], [input_w, input_h, 1], 0, guide_layers, 0)?;
+        }
+        match pipeline {
+            DenoisePipeline::Fragment(pipeline) => denoise_frag::record(&mut builder, pipeline, &bindings, push_constants)?,
+            DenoisePipeline::Compute(pipeline) => denoise_compute::record(&mut builder, pipeline.clone(), &bindings, push_constants)?,
+        }
+        builder
+            .copy_image_to_buffer(bindings.result_img.clone(), result_buf.clone())?;
+        let fence = builder.build()?
+            .execute(self.queue.clone())?
+            .then_signal_fence_and_flush()?;
+
+        Ok(PendingTile { fence, input_buf, input_len, guide_buf, guide_len, result_buf, result_len, binding_key, bindings, origin,
+                         size: [result_w, result_h] })
+    }
+}
+
+/// Tile submitted to the queue, owning everything the GPU is still using.
+struct PendingTile {
+    fence: TileFence,
+    input_buf: Arc<CpuAccessibleBuffer<[f32]>>,
+    input_len: usize,
+    guide_buf: Option<Arc<CpuAccessibleBuffer<[f32]>>>,
+    guide_len: usize,
+    result_buf: Arc<CpuAccessibleBuffer<[u8]>>,
+    result_len: usize,
+    binding_key: BindingKey,
+    bindings: ImageBindings,
+    /// Position of the tile in the image
+    origin: [u32; 2],
+    size: [u32; 2],
+}
+
+impl PendingTile {
+    /// Staging buffers plus images held until the tile is finished, counted like the memory budget.
+    fn memory_bytes(&self) -> u64 {
+        ((self.input_len + self.guide_len) * std::mem::size_of::<f32>() + self.result_len) as u64 + binding_bytes(&self.binding_key)
+    }
+
+    /// Blocks until the tile is done, copies its result out and gives the resources back to the pools of `gpu`.
+    fn finish(self, gpu: &GpuContext) -> Result<FinishedTile, DenoiseError> {
+        self.fence.wait(None)?;
+        let result = self.result_buf.read()?.to_vec();
+        let (origin, size) = (self.origin, self.size);
+        gpu.release(self);
+        Ok(FinishedTile { result, origin, size })
+    }
+}
+
+/// Result of a tile read back from the GPU.
+struct FinishedTile {
+    result: Vec<u8>,
+    origin: [u32; 2],
+    size: [u32; 2],
+}
+
+/// Image whose tiles are all submitted, [`PendingImage::wait`] collects the result.
+struct PendingImage<D> {
+    /// Tiles waited on while later ones were submitted
+    finished: Vec<FinishedTile>,
+    tiles: Vec<PendingTile>,
+    img_w: u32,
+    img_h: u32,
+    num_input_samples: usize,
+    num_output_samples: usize,
+    sample: PhantomData<D>,
+}
+
+impl<D: Denoiseable> PendingImage<D> {
+    /// Staging buffers plus images held until the image is waited on, counted like the memory budget.
+    fn memory_bytes(&self) -> u64 {
+        self.tiles.iter().map(PendingTile::memory_bytes).sum()
+    }
+
+    /// Blocks until every tile is done, stitches them and gives the resources back to the pools of `gpu`.
+    fn wait(self, gpu: &GpuContext) -> Result<Vec<D>, DenoiseError> {
+        let num_samples = self.num_output_samples;
+        let mut result = vec![D::zero(); self.img_w as usize * self.img_h as usize * num_samples];
+        let mut finished = self.finished;
+        for tile in self.tiles {
+            finished.push(tile.finish(gpu)?);
+        }
+        for tile in finished {
+            let tile_result: Vec<D> = bytemuck::pod_collect_to_vec(&tile.result);
+
+            let row_len = tile.size[0] as usize * num_samples;
+            for (y, row) in tile_result.chunks(row_len).enumerate() {
+                let dst = ((tile.origin[1] as usize + y) * self.img_w as usize + tile.origin[0] as usize) * num_samples;
+                result[dst..dst + row_len].copy_from_slice(row);
+            }
+        }
+
+        Ok(match self.num_input_samples {
+            3 => result.chunks(4).flat_map(|v| vec![v[0],v[1],v[2]]).collect(),
+            _ => result
+        })
+    }
+}
+
+/// Borrowed image for [`Denoiser::denoise_batch`], `buf` holds `width` x `height` pixels of 1, 2, 3 or 4 interleaved channels.
+#[derive(Debug, Copy, Clone)]
+pub struct ImageRef<'a, D> {
+    pub buf: &'a [D],
+    pub width: u32,
+    pub height: u32,
+}
+
+impl<'a, D> ImageRef<'a, D> {
+    pub fn new(buf: &'a [D], width: u32, height: u32) -> Self {
+        Self { buf, width, height }
+    }
+}
+
+/// Which device to run on when a [`Denoiser`] is created.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum BackendPolicy {
+    ///Use Vulkan when a suitable device exists, fall back to the CPU otherwise
+    PreferGpu,
+    ///Fail when there's no suitable Vulkan device
+    RequireGpu,
+    ///Never initialise Vulkan
+    CpuOnly,
+}
+
+/// Backend a [`Denoiser`] actually runs on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    Gpu,
+    Cpu,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Gpu => write!(f, "GPU"),
+            Backend::Cpu => write!(f, "CPU"),
+        }
+    }
+}
+
+/// Long-lived denoising context.
+///
+/// On the GPU it owns the Vulkan device and queue, caches compiled pipelines and pools staging buffers
+/// and images by size, so it is meant to be created once and reused for every image. It's `Send + Sync`,
+/// calls from several threads share the caches.
+pub struct Denoiser {
+    gpu: Option<Arc<GpuContext>>,
+    gpu_error: Option<DenoiseError>,
+    policy: BackendPolicy,
+}
+
+impl Denoiser {
+    /// Same as [`Denoiser::with_policy`] with [`BackendPolicy::PreferGpu`].
+    pub fn new() -> Result<Self, DenoiseError> {
+        Self::with_policy(BackendPolicy::PreferGpu)
+    }
+
+    pub fn with_policy(policy: BackendPolicy) -> Result<Self, DenoiseError> {
+        Self::with_device_selector(policy, &DeviceSelector::Best)
+    }
+
+    /// Runs on the device picked by `selector`, when it isn't usable the `policy` decides whether to fall back to the CPU.
+    pub fn with_device_selector(policy: BackendPolicy, selector: &DeviceSelector) -> Result<Self, DenoiseError> {
+        if policy == BackendPolicy::CpuOnly {
+            return Ok(Self { gpu: None, gpu_error: None, policy });
+        }
+        match vlk_init_with(selector).and_then(|(device, queue)| GpuContext::new(device, queue)) {
+            Ok(gpu) => Ok(Self { gpu: Some(Arc::new(gpu)), gpu_error: None, policy }),
+            Err(e) if policy == BackendPolicy::PreferGpu => {
+                #[cfg(debug_assertions)] eprintln!("Falling back to CPU: {}", e);
+                Ok(Self { gpu: None, gpu_error: Some(e), policy })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Creates the context on top of an already initialised device and queue.
+    pub fn with_device(device: Arc<Device>, queue: Arc<Queue>) -> Result<Self, DenoiseError> {
+        Ok(Self { gpu: Some(Arc::new(GpuContext::new(device, queue)?)), gpu_error: None, policy: BackendPolicy::PreferGpu })
+    }
+
+    /// Caps the memory a single GPU pass may use, defaults to half of the largest device-local heap.
+    ///
+    /// Images needing more, or larger than the device's maximum image size, are processed in overlapping
+    /// tiles that give the result of one pass within a code value. Buffers and images kept for reuse between
+    /// calls are trimmed to it too, least recently used first. The CPU backend ignores it.
+    pub fn with_memory_budget(self, bytes: u64) -> Self {
+        if let Some(gpu) = &self.gpu {
+            gpu.memory_budget.store(bytes, Ordering::Relaxed);
+        }
+        self
+    }
+
+    /// Most bytes of staging buffers and images a single image held on the GPU at once, counted like the memory budget.
+    ///
+    /// Tiled images stay within the budget plus one tile. Zero on the CPU backend or before the first GPU call.
+    pub fn peak_image_memory(&self) -> u64 {
+        self.gpu.as_ref().map_or(0, |gpu| gpu.peak_image_memory.load(Ordering::Relaxed))
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self.gpu {
+            Some(_) => Backend::Gpu,
+            None => Backend::Cpu,
+        }
+    }
+
+    /// Why the GPU wasn't used when [`BackendPolicy::PreferGpu`] fell back to the CPU.
+    pub fn fallback_reason(&self) -> Option<&DenoiseError> {
+        self.gpu_error.as_ref()
+    }
+
+    pub fn device(&self) -> Option<Arc<Device>> {
+        self.gpu.as_ref().map(|gpu| gpu.device.clone())
+    }
+
+    pub fn queue(&self) -> Option<Arc<Queue>> {
+        self.gpu.as_ref().map(|gpu| gpu.queue.clone())
+    }
+
+    /// [`UsingShader::Cpu`] would silently bypass the device [`BackendPolicy::RequireGpu`] asked for.
+    fn check_shader_type(&self, shader_type: UsingShader) -> Result<(), DenoiseError> {
+        match (self.policy, shader_type) {
+            (BackendPolicy::RequireGpu, UsingShader::Cpu) =>
+                Err(DenoiseError::InvalidParameter("CPU shader type can't run on a denoiser requiring the GPU".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Denoises `buf` holding `img_w` x `img_h` pixels of 1, 2, 3 or 4 interleaved channels.
+    ///
+    /// `shader_type` picks the GPU path, on the CPU backend every shader type runs the CPU implementation.
+    /// [`UsingShader::Cpu`] is rejected when the denoiser was created with [`BackendPolicy::RequireGpu`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn denoise<D>(&self, buf: &[D], img_w: u32, img_h: u32, shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo) -> Result<Vec<D>, DenoiseError>
+    where D: Denoiseable
+    {
+        self.check_shader_type(shader_type)?;
+        match (&self.gpu, shader_type) {
+            (Some(gpu), UsingShader::Fragment | UsingShader::Compute) => gpu.denoise(buf, img_w, img_h, shader_type, params, use_hsv, algo),
+            _ => denoise_cpu::denoise(buf, img_w, img_h, params, use_hsv, algo, None),
+        }
+    }
+
+    /// Joint bilateral filter: [`Algo::Smart`] averaging `buf` with edge-stopping weights computed from `guides` instead of `buf`.
+    ///
+    /// Every guide has the size of `buf` and its own threshold, the weight of a neighbour is the product of the guide
+    /// ones, so edges of any guide are kept. `params` gives sigma and kSigma, its threshold isn't used. Colour is
+    /// averaged in RGB, combining it with another [`ColorSpace`] fails.
+    pub fn denoise_guided<D>(&self, buf: &[D], img_w: u32, img_h: u32, guides: &[Guide], shader_type: UsingShader, params: DenoiseParams)
+        -> Result<Vec<D>, DenoiseError>
+    where D: Denoiseable
+    {
+        self.check_shader_type(shader_type)?;
+        let guides = guide::layers(guides, img_w, img_h)?;
+        match (&self.gpu, shader_type) {
+            (Some(gpu), UsingShader::Fragment | UsingShader::Compute) =>
+                gpu.submit(buf, img_w, img_h, shader_type, params, false, Algo::Smart, Some(&guides))?.wait(gpu),
+            _ => denoise_cpu::denoise(buf, img_w, img_h, params, false, Algo::Smart, Some(&guides)),
+        }
+    }
+
+    /// Same as [`Denoiser::denoise`] but returns as soon as the work is submitted.
+    ///
+    /// `buf` is copied before returning, the handle resolves once the GPU signals its fence. Errors found while
+    /// preparing the submission are returned right away, the ones happening on the device through the handle.
+    /// Images split in tiles wait for earlier tiles once the memory budget is reached, so the call may block
+    /// until all but the last few tiles are done. On the CPU backend the filtering runs on a new thread.
+    #[allow(clippy::too_many_arguments)]
+    pub fn denoise_async<D>(&self, buf: &[D], img_w: u32, img_h: u32, shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo)
+        -> Result<DenoiseHandle<D>, DenoiseError>
+    where D: Denoiseable
+    {
+        self.check_shader_type(shader_type)?;
+        let (completion, handle) = handle::channel();
+        match (&self.gpu, shader_type) {
+            (Some(gpu), UsingShader::Fragment | UsingShader::Compute) => {
+                let pending = gpu.submit(buf, img_w, img_h, shader_type, params, use_hsv, algo, None)?;
+                let context = gpu.clone();
+                gpu.wait_in_background(Box::new(move || completion.complete(pending.wait(&context))))?;
+            }
+            _ => {
+                let buf = buf.to_vec();
+                thread::Builder::new()
+                    .name("denoise-cpu".to_string())
+                    .spawn(move || completion.complete(denoise_cpu::denoise(&buf, img_w, img_h, params, use_hsv, algo, None)))
+                    .map_err(|_| DenoiseError::OutOfHostMemory)?;
+            }
+        }
+        Ok(handle)
+    }
+
+    /// Denoises every image of `images` with the same settings, results are in the same order.
+    ///
+    /// On the GPU later images are submitted while earlier ones are filtered, so their uploads, filtering and
+    /// downloads overlap. Once the images in flight hold more than the memory budget (see
+    /// [`Denoiser::with_memory_budget`]) the oldest is waited on before going on, so memory use is bounded
+    /// by the budget plus one image. Every image still gets its own command buffers. A failing image only
+    /// fails its own entry.
+    pub fn denoise_batch<D>(&self, images: &[ImageRef<D>], shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo)
+        -> Vec<Result<Vec<D>, DenoiseError>>
+    where D: Denoiseable
+    {
+        if let Err(e) = self.check_shader_type(shader_type) {
+            return images.iter().map(|_| Err(e.clone())).collect();
+        }
+        match (&self.gpu, shader_type) {
+            (Some(gpu), UsingShader::Fragment | UsingShader::Compute) => {
+                let memory_budget = gpu.memory_budget.load(Ordering::Relaxed);
+                let mut results = Vec::with_capacity(images.len());
+                let mut in_flight = VecDeque::new();
+                let mut in_flight_bytes = 0;
+                for img in images {
+                    let pending = gpu.submit(img.buf, img.width, img.height, shader_type, params, use_hsv, algo, None);
+                    in_flight_bytes += pending.as_ref().map_or(0, PendingImage::memory_bytes);
+                    in_flight.push_back(pending);
+                    //The newest image stays in flight, it's the one overlapping with the wait
+                    while in_flight_bytes > memory_budget && in_flight.len() > 1 {
+                        let pending = in_flight.pop_front().unwrap();
+                        in_flight_bytes -= pending.as_ref().map_or(0, PendingImage::memory_bytes);
+                        results.push(pending.and_then(|pending| pending.wait(gpu)));
+                    }
+                }
+                results.extend(in_flight.into_iter().map(|pending| pending.and_then(|pending| pending.wait(gpu))));
+                results
+            }
+            _ => images.iter()
+                .map(|img| denoise_cpu::denoise(img.buf, img.width, img.height, params, use_hsv, algo, None))
+                .collect(),
+        }
+    }
+}
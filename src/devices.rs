@@ -0,0 +1,146 @@
+use std::fmt;
+use std::str::FromStr;
+use vulkano::device::physical::{PhysicalDevice, PhysicalDeviceType};
+use vulkano::Version;
+use crate::{create_instance, required_device_extensions, DenoiseError};
+
+/// Description of a physical device as reported by the driver.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    /// Position in the driver's enumeration order, used by [`DeviceSelector::Index`]
+    pub index: usize,
+    pub name: String,
+    pub device_type: PhysicalDeviceType,
+    pub vendor_id: u32,
+    pub api_version: Version,
+    pub max_image_dimension: u32,
+    /// Size in bytes and whether the heap is device local
+    pub memory_heaps: Vec<(u64, bool)>,
+    /// Has the extensions and the compute queue the denoiser needs
+    pub supported: bool,
+}
+
+impl DeviceInfo {
+    fn new(p: &PhysicalDevice) -> Self {
+        let properties = p.properties();
+        Self {
+            index: p.index(),
+            name: properties.device_name.clone(),
+            device_type: properties.device_type,
+            vendor_id: properties.vendor_id,
+            api_version: p.api_version(),
+            max_image_dimension: properties.max_image_dimension2_d,
+            memory_heaps: p.memory_heaps().map(|h| (h.size(), h.is_device_local())).collect(),
+            supported: is_supported(p),
+        }
+    }
+
+    pub fn vendor_name(&self) -> &'static str {
+        match self.vendor_id {
+            0x1002 => "AMD",
+            0x1010 => "ImgTec",
+            0x10DE => "NVIDIA",
+            0x13B5 => "ARM",
+            0x5143 => "Qualcomm",
+            0x8086 => "Intel",
+            0x10005 => "Mesa",
+            _ => "unknown vendor",
+        }
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} ({}, {}, Vulkan {}), max image {}px, memory heaps:",
+               self.index, self.name, device_type_name(self.device_type), self.vendor_name(),
+               self.api_version, self.max_image_dimension)?;
+        for (size, device_local) in self.memory_heaps.iter() {
+            write!(f, " {} MiB{}", size / (1024 * 1024), if *device_local {" device local"} else {""})?;
+        }
+        if !self.supported {
+            write!(f, " [unsupported]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which physical device [`crate::vlk_init_with`] should use.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DeviceSelector {
+    /// Supported device of the most capable type, discrete GPUs first
+    #[default]
+    Best,
+    /// Device at this position of [`list_devices`]
+    Index(usize),
+    /// First supported device in the order of [`list_devices`] whose name contains this string, case insensitive
+    Name(String),
+    /// Best device of this type
+    Type(PhysicalDeviceType),
+}
+
+impl DeviceSelector {
+    pub(crate) fn matches(&self, p: &PhysicalDevice) -> bool {
+        match self {
+            DeviceSelector::Best => true,
+            DeviceSelector::Index(index) => p.index() == *index,
+            DeviceSelector::Name(name) => p.properties().device_name.to_lowercase().contains(&name.to_lowercase()),
+            DeviceSelector::Type(device_type) => p.properties().device_type == *device_type,
+        }
+    }
+}
+
+/// Parses an index, a device type (`discrete-gpu`, `integrated-gpu`, `virtual-gpu`, `cpu`, `other`)
+/// or anything else as a name substring.
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty device selector".to_string());
+        }
+        if let Ok(index) = s.parse::<usize>() {
+            return Ok(DeviceSelector::Index(index));
+        }
+        Ok(match s {
+            "best" => DeviceSelector::Best,
+            "discrete-gpu" => DeviceSelector::Type(PhysicalDeviceType::DiscreteGpu),
+            "integrated-gpu" => DeviceSelector::Type(PhysicalDeviceType::IntegratedGpu),
+            "virtual-gpu" => DeviceSelector::Type(PhysicalDeviceType::VirtualGpu),
+            "cpu" => DeviceSelector::Type(PhysicalDeviceType::Cpu),
+            "other" => DeviceSelector::Type(PhysicalDeviceType::Other),
+            name => DeviceSelector::Name(name.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Best => write!(f, "best device"),
+            DeviceSelector::Index(index) => write!(f, "device #{}", index),
+            DeviceSelector::Name(name) => write!(f, "device named like \"{}\"", name),
+            DeviceSelector::Type(device_type) => write!(f, "{} device", device_type_name(*device_type)),
+        }
+    }
+}
+
+fn device_type_name(device_type: PhysicalDeviceType) -> &'static str {
+    match device_type {
+        PhysicalDeviceType::DiscreteGpu => "discrete-gpu",
+        PhysicalDeviceType::IntegratedGpu => "integrated-gpu",
+        PhysicalDeviceType::VirtualGpu => "virtual-gpu",
+        PhysicalDeviceType::Cpu => "cpu",
+        PhysicalDeviceType::Other => "other",
+    }
+}
+
+pub(crate) fn is_supported(p: &PhysicalDevice) -> bool {
+    p.supported_extensions().is_superset_of(&required_device_extensions())
+        && p.queue_families().any(|q| q.supports_compute())
+}
+
+/// Enumerates every physical device of the Vulkan implementation, including unsupported ones.
+pub fn list_devices() -> Result<Vec<DeviceInfo>, DenoiseError> {
+    let instance = create_instance()?;
+    Ok(PhysicalDevice::enumerate(&instance).map(|p| DeviceInfo::new(&p)).collect())
+}
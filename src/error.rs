@@ -0,0 +1,150 @@
+use std::error::Error;
+use std::fmt;
+use vulkano::buffer::cpu_access::{ReadLockError, WriteLockError};
+use vulkano::command_buffer::{AutoCommandBufferBuilderContextError, BeginRenderPassError, BuildError, CommandBufferExecError,
+                              CopyBufferImageError, DispatchError, DrawError};
+use vulkano::descriptor_set::DescriptorSetCreationError;
+use vulkano::device::DeviceCreationError;
+use vulkano::image::ImageCreationError;
+use vulkano::image::view::ImageViewCreationError;
+use vulkano::instance::InstanceCreationError;
+use vulkano::memory::DeviceMemoryAllocationError;
+use vulkano::OomError;
+use vulkano::pipeline::compute::ComputePipelineCreationError;
+use vulkano::pipeline::graphics::GraphicsPipelineCreationError;
+use vulkano::render_pass::{FramebufferCreationError, RenderPassCreationError};
+use vulkano::sampler::SamplerCreationError;
+use vulkano::shader::ShaderCreationError;
+use vulkano::sync::FlushError;
+
+/// Everything that can go wrong while denoising an image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DenoiseError {
+    /// No Vulkan implementation or no physical device with the required extensions and a compute queue.
+    NoSuitableDevice(String),
+    /// The combination of sample type and channel count has no matching image format or shader.
+    UnsupportedChannelLayout { channels: usize, sample_type: &'static str },
+    /// Length of the buffer doesn't correspond to the image dimensions.
+    BufferSizeMismatch { width: u32, height: u32, len: usize },
+    /// Denoise parameter out of its valid range.
+    InvalidParameter(String),
+    OutOfDeviceMemory,
+    OutOfHostMemory,
+    /// Shader module, render pass or pipeline couldn't be created.
+    PipelineCreation(String),
+    /// Connection to the device has been lost, the `Denoiser` has to be recreated.
+    DeviceLost,
+    /// Any other Vulkan failure.
+    Vulkan(String),
+}
+
+impl Error for DenoiseError {}
+
+impl fmt::Display for DenoiseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuitableDevice(reason) => write!(f, "no suitable Vulkan device: {}", reason),
+            Self::UnsupportedChannelLayout { channels, sample_type } =>
+                write!(f, "{} channel(s) of {} samples are not supported", channels, sample_type),
+            Self::BufferSizeMismatch { width, height, len } =>
+                write!(f, "buffer of {} samples doesn't match {}x{} image", len, width, height),
+            Self::InvalidParameter(reason) => write!(f, "invalid parameter: {}", reason),
+            Self::OutOfDeviceMemory => write!(f, "out of device memory"),
+            Self::OutOfHostMemory => write!(f, "out of host memory"),
+            Self::PipelineCreation(reason) => write!(f, "failed to create denoise pipeline: {}", reason),
+            Self::DeviceLost => write!(f, "connection to the device has been lost"),
+            Self::Vulkan(reason) => write!(f, "vulkan error: {}", reason),
+        }
+    }
+}
+
+impl From<OomError> for DenoiseError {
+    fn from(err: OomError) -> Self {
+        match err {
+            OomError::OutOfHostMemory => Self::OutOfHostMemory,
+            OomError::OutOfDeviceMemory => Self::OutOfDeviceMemory,
+        }
+    }
+}
+
+impl From<DeviceMemoryAllocationError> for DenoiseError {
+    fn from(err: DeviceMemoryAllocationError) -> Self {
+        match err {
+            DeviceMemoryAllocationError::OomError(err) => err.into(),
+            err => Self::Vulkan(err.to_string()),
+        }
+    }
+}
+
+impl From<ImageCreationError> for DenoiseError {
+    fn from(err: ImageCreationError) -> Self {
+        match err {
+            ImageCreationError::AllocError(err) => err.into(),
+            err => Self::Vulkan(err.to_string()),
+        }
+    }
+}
+
+impl From<FlushError> for DenoiseError {
+    fn from(err: FlushError) -> Self {
+        match err {
+            FlushError::OomError(err) => err.into(),
+            FlushError::DeviceLost => Self::DeviceLost,
+            err => Self::Vulkan(err.to_string()),
+        }
+    }
+}
+
+impl From<InstanceCreationError> for DenoiseError {
+    fn from(err: InstanceCreationError) -> Self {
+        Self::NoSuitableDevice(err.to_string())
+    }
+}
+
+impl From<DeviceCreationError> for DenoiseError {
+    fn from(err: DeviceCreationError) -> Self {
+        match err {
+            DeviceCreationError::DeviceLost => Self::DeviceLost,
+            DeviceCreationError::OutOfHostMemory => Self::OutOfHostMemory,
+            DeviceCreationError::OutOfDeviceMemory => Self::OutOfDeviceMemory,
+            err => Self::NoSuitableDevice(err.to_string()),
+        }
+    }
+}
+
+/// Errors carrying an `OomError` variant, everything else maps to `$variant` with the error description.
+macro_rules! from_vulkano_error {
+    ($variant:ident: $($err:ident),+ $(,)?) => {
+        $(
+            impl From<$err> for DenoiseError {
+                fn from(err: $err) -> Self {
+                    match err {
+                        $err::OomError(err) => err.into(),
+                        err => Self::$variant(err.to_string()),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+from_vulkano_error!(PipelineCreation: ShaderCreationError, ComputePipelineCreationError, RenderPassCreationError);
+from_vulkano_error!(Vulkan: SamplerCreationError, ImageViewCreationError, DescriptorSetCreationError, FramebufferCreationError,
+                    BuildError);
+
+/// Errors with no memory-related variants.
+macro_rules! from_other_error {
+    ($variant:ident: $($err:ident),+ $(,)?) => {
+        $(
+            impl From<$err> for DenoiseError {
+                fn from(err: $err) -> Self {
+                    Self::$variant(err.to_string())
+                }
+            }
+        )+
+    };
+}
+
+from_other_error!(PipelineCreation: GraphicsPipelineCreationError);
+from_other_error!(Vulkan: CommandBufferExecError, CopyBufferImageError, DispatchError, DrawError, BeginRenderPassError,
+                  AutoCommandBufferBuilderContextError, ReadLockError, WriteLockError);
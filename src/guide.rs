@@ -0,0 +1,50 @@
+use crate::DenoiseError;
+
+/// Image [`Denoiser::denoise_guided`](crate::Denoiser::denoise_guided) computes edge-stopping weights from instead of the noisy input.
+///
+/// Typical guides are the albedo, normals and depth a renderer writes next to its beauty pass: they have the edges
+/// of the scene without its noise.
+#[derive(Debug, Copy, Clone)]
+pub struct Guide<'a> {
+    /// Same width and height as the denoised image, 1 to 4 interleaved channels in any unit
+    pub buf: &'a [f32],
+    /// Guide difference, in the units of `buf`, that plays the role of the [`Algo::Smart`](crate::Algo::Smart) threshold
+    pub threshold: f32,
+}
+
+impl<'a> Guide<'a> {
+    pub fn new(buf: &'a [f32], threshold: f32) -> Self {
+        Self { buf, threshold }
+    }
+}
+
+/// Every guide divided by its threshold and padded to 4 channels, one `img_w` x `img_h` layer after the other.
+///
+/// Halving the summed squared differences of the scaled guides gives the exponent of the product of their range weights.
+pub(crate) fn layers(guides: &[Guide], img_w: u32, img_h: u32) -> Result<Vec<[f32; 4]>, DenoiseError> {
+    if guides.is_empty() {
+        return Err(DenoiseError::InvalidParameter("guided filtering needs at least one guide".to_string()));
+    }
+    let num_pixels = img_w as usize * img_h as usize;
+    let mut layers = Vec::with_capacity(num_pixels * guides.len());
+    for guide in guides {
+        if num_pixels == 0 || guide.buf.is_empty() || !guide.buf.len().is_multiple_of(num_pixels) {
+            return Err(DenoiseError::BufferSizeMismatch { width: img_w, height: img_h, len: guide.buf.len() });
+        }
+        let channels = guide.buf.len() / num_pixels;
+        if channels > 4 {
+            return Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "f32" });
+        }
+        if !guide.threshold.is_finite() || guide.threshold <= 0.0 {
+            return Err(DenoiseError::InvalidParameter(format!("guide threshold has to be positive, got {}", guide.threshold)));
+        }
+        layers.extend(guide.buf.chunks(channels).map(|px| {
+            let mut scaled = [0.0; 4];
+            for (s, v) in scaled.iter_mut().zip(px) {
+                *s = v / guide.threshold;
+            }
+            scaled
+        }));
+    }
+    Ok(layers)
+}
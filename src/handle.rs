@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use crate::DenoiseError;
+
+struct Slot<D> {
+    result: Option<Result<Vec<D>, DenoiseError>>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+struct Shared<D> {
+    slot: Mutex<Slot<D>>,
+    finished: Condvar,
+}
+
+impl<D> Shared<D> {
+    fn lock(&self) -> MutexGuard<'_, Slot<D>> {
+        self.slot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Denoise call running in the background, returned by [`crate::Denoiser::denoise_async`].
+///
+/// Either `.await` it, poll [`DenoiseHandle::is_finished`] or block in [`DenoiseHandle::wait`].
+/// It doesn't depend on any async runtime, completion wakes the task that polled it last.
+pub struct DenoiseHandle<D> {
+    shared: Arc<Shared<D>>,
+}
+
+/// Side of a [`DenoiseHandle`] kept by whoever does the work.
+pub(crate) struct Completion<D> {
+    shared: Arc<Shared<D>>,
+}
+
+pub(crate) fn channel<D>() -> (Completion<D>, DenoiseHandle<D>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot { result: None, finished: false, waker: None }),
+        finished: Condvar::new(),
+    });
+    (Completion { shared: shared.clone() }, DenoiseHandle { shared })
+}
+
+impl<D> Completion<D> {
+    pub(crate) fn complete(self, result: Result<Vec<D>, DenoiseError>) {
+        self.finish(result);
+    }
+
+    fn finish(&self, result: Result<Vec<D>, DenoiseError>) {
+        let waker = {
+            let mut slot = self.shared.lock();
+            if slot.finished {
+                return;
+            }
+            slot.result = Some(result);
+            slot.finished = true;
+            slot.waker.take()
+        };
+        self.shared.finished.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<D> Drop for Completion<D> {
+    //Work that panicked or got dropped must not leave the handle waiting forever
+    fn drop(&mut self) {
+        self.finish(Err(DenoiseError::Vulkan("denoise job ended without a result".to_string())));
+    }
+}
+
+impl<D> DenoiseHandle<D> {
+    /// Whether the result is ready, [`DenoiseHandle::wait`] won't block once it is.
+    pub fn is_finished(&self) -> bool {
+        self.shared.lock().finished
+    }
+
+    /// Blocks the calling thread until the result is ready.
+    pub fn wait(self) -> Result<Vec<D>, DenoiseError> {
+        let mut slot = self.shared.lock();
+        while !slot.finished {
+            slot = self.shared.finished.wait(slot).unwrap_or_else(|e| e.into_inner());
+        }
+        slot.result.take().expect("DenoiseHandle result was already taken")
+    }
+}
+
+impl<D> Future for DenoiseHandle<D> {
+    type Output = Result<Vec<D>, DenoiseError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.shared.lock();
+        if slot.finished {
+            return Poll::Ready(slot.result.take().expect("DenoiseHandle polled after completion"));
+        }
+        slot.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -0,0 +1,51 @@
+use std::ops::Deref;
+use image::{DynamicImage, ImageBuffer, Pixel};
+use crate::{Algo, DenoiseError, DenoiseParams, Denoiseable, Denoiser, UsingShader};
+
+/// Everything [`Denoiser::denoise`] takes besides the pixels.
+#[derive(Debug, Copy, Clone)]
+pub struct Options {
+    pub shader_type: UsingShader,
+    pub params: DenoiseParams,
+    pub use_hsv: bool,
+    pub algo: Algo,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { shader_type: UsingShader::Compute, params: DenoiseParams::default(), use_hsv: false, algo: Algo::Smart }
+    }
+}
+
+impl Denoiser {
+    /// Denoises any `image` crate buffer of 8-bit, 16-bit or `f32` samples, the channel count comes from the pixel type.
+    pub fn denoise_buffer<P, C>(&self, img: &ImageBuffer<P, C>, options: &Options) -> Result<ImageBuffer<P, Vec<P::Subpixel>>, DenoiseError>
+    where P: Pixel,
+          P::Subpixel: Denoiseable,
+          C: Deref<Target = [P::Subpixel]>
+    {
+        let (width, height) = img.dimensions();
+        let res = self.denoise(img.as_raw(), width, height, options.shader_type, options.params, options.use_hsv, options.algo)?;
+        ImageBuffer::from_raw(width, height, res)
+            .ok_or(DenoiseError::BufferSizeMismatch { width, height, len: img.as_raw().len() })
+    }
+
+    /// Denoises a [`DynamicImage`] keeping its variant, so bit depth and channels stay as they are.
+    ///
+    /// Variants added to the `image` crate after this was written are filtered as `Rgba32F`.
+    pub fn denoise_image(&self, img: &DynamicImage, options: &Options) -> Result<DynamicImage, DenoiseError> {
+        Ok(match img {
+            DynamicImage::ImageLuma8(img) => DynamicImage::ImageLuma8(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageLumaA8(img) => DynamicImage::ImageLumaA8(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageRgb8(img) => DynamicImage::ImageRgb8(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageRgba8(img) => DynamicImage::ImageRgba8(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageLuma16(img) => DynamicImage::ImageLuma16(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageLumaA16(img) => DynamicImage::ImageLumaA16(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageRgb16(img) => DynamicImage::ImageRgb16(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageRgba16(img) => DynamicImage::ImageRgba16(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageRgb32F(img) => DynamicImage::ImageRgb32F(self.denoise_buffer(img, options)?),
+            DynamicImage::ImageRgba32F(img) => DynamicImage::ImageRgba32F(self.denoise_buffer(img, options)?),
+            img => DynamicImage::ImageRgba32F(self.denoise_buffer(&img.to_rgba32f(), options)?),
+        })
+    }
+}
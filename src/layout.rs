@@ -0,0 +1,133 @@
+use std::mem::size_of;
+use crate::{Algo, DenoiseError, DenoiseParams, Denoiseable, Denoiser, UsingShader};
+
+/// Order of the interleaved samples within a pixel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum ChannelOrder {
+    /// Gray, gray+alpha, RGB or RGBA
+    #[default]
+    Rgba,
+    /// BGR or BGRA
+    Bgra,
+    /// Alpha first, alpha+gray or ARGB
+    Argb,
+    /// ABGR
+    Abgr,
+}
+
+impl ChannelOrder {
+    /// Position within the pixel of every channel in gray/RGB then alpha order.
+    fn positions(self, channels: usize) -> Option<[usize; 4]> {
+        match (self, channels) {
+            (ChannelOrder::Rgba, 1..=4) => Some([0, 1, 2, 3]),
+            (ChannelOrder::Bgra, 3 | 4) => Some([2, 1, 0, 3]),
+            (ChannelOrder::Argb, 2) => Some([1, 0, 0, 0]),
+            (ChannelOrder::Argb, 4) => Some([1, 2, 3, 0]),
+            (ChannelOrder::Abgr, 4) => Some([3, 2, 1, 0]),
+            _ => None,
+        }
+    }
+}
+
+/// How the pixels of an image are laid out in memory, for buffers that aren't tightly packed RGBA.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Layout {
+    width: u32,
+    height: u32,
+    channels: usize,
+    /// Bytes from the start of a row to the start of the next one, `None` for packed rows
+    row_stride: Option<usize>,
+    channel_order: ChannelOrder,
+}
+
+impl Layout {
+    /// Packed rows of `channels` samples per pixel in [`ChannelOrder::Rgba`] order.
+    pub fn new(width: u32, height: u32, channels: usize) -> Self {
+        Self { width, height, channels, row_stride: None, channel_order: ChannelOrder::default() }
+    }
+
+    pub fn with_row_stride(self, bytes: usize) -> Self {
+        Self { row_stride: Some(bytes), ..self }
+    }
+
+    pub fn with_channel_order(self, channel_order: ChannelOrder) -> Self {
+        Self { channel_order, ..self }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn row_len(&self) -> usize {
+        self.width as usize * self.channels
+    }
+
+    /// Row stride in samples of `D`.
+    fn stride<D>(&self) -> Result<usize, DenoiseError> {
+        let Some(bytes) = self.row_stride else {
+            return Ok(self.row_len());
+        };
+        if !bytes.is_multiple_of(size_of::<D>()) || bytes / size_of::<D>() < self.row_len() {
+            return Err(DenoiseError::InvalidParameter(format!("row stride of {} bytes doesn't fit {} pixels of {} {} samples",
+                                                              bytes, self.width, self.channels, std::any::type_name::<D>())));
+        }
+        Ok(bytes / size_of::<D>())
+    }
+
+    /// Checks the layout and that `len` samples hold exactly the image, the last row may leave out its padding.
+    fn validate<D>(&self, len: usize) -> Result<(usize, [usize; 4]), DenoiseError> {
+        let positions = self.channel_order.positions(self.channels)
+            .ok_or(DenoiseError::UnsupportedChannelLayout { channels: self.channels, sample_type: std::any::type_name::<D>() })?;
+        let stride = self.stride::<D>()?;
+        let rows = self.height as usize;
+        let padded = rows * stride;
+        let unpadded = rows.saturating_sub(1) * stride + self.row_len();
+        if rows == 0 || self.width == 0 || (len != padded && len != unpadded) {
+            return Err(DenoiseError::BufferSizeMismatch { width: self.width, height: self.height, len });
+        }
+        Ok((stride, positions))
+    }
+}
+
+impl Denoiser {
+    /// Denoises `input` laid out as `layout` into `output` with the same layout.
+    ///
+    /// Row padding of `output` is left untouched. Lengths that don't match the layout are rejected
+    /// instead of guessing the channel count from them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn denoise_into<D>(&self, input: &[D], output: &mut [D], layout: &Layout, shader_type: UsingShader, params: DenoiseParams,
+                           use_hsv: bool, algo: Algo) -> Result<(), DenoiseError>
+    where D: Denoiseable
+    {
+        let (stride, positions) = layout.validate::<D>(input.len())?;
+        layout.validate::<D>(output.len())?;
+        let channels = layout.channels;
+        let row_len = layout.row_len();
+
+        let mut packed = Vec::with_capacity(row_len * layout.height as usize);
+        for row in input.chunks(stride) {
+            for px in row[..row_len].chunks_exact(channels) {
+                packed.extend(positions[..channels].iter().map(|&pos| px[pos]));
+            }
+        }
+
+        let res = self.denoise(&packed, layout.width, layout.height, shader_type, params, use_hsv, algo)?;
+
+        for (row, res_row) in output.chunks_mut(stride).zip(res.chunks_exact(row_len)) {
+            for (px, res_px) in row[..row_len].chunks_exact_mut(channels).zip(res_row.chunks_exact(channels)) {
+                for (&pos, v) in positions[..channels].iter().zip(res_px) {
+                    px[pos] = *v;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,52 +1,83 @@
 extern crate core;
 
 mod vertex_shader;
+mod color_space;
 mod denoise_compute;
+mod denoise_cpu;
 mod denoise_frag;
+mod denoiser;
+mod devices;
+mod error;
 mod generated;
-
+mod guide;
+mod handle;
+mod images;
+mod layout;
+mod noise;
+
+pub use denoiser::{Backend, BackendPolicy, Denoiser, ImageRef};
+pub use devices::{list_devices, DeviceInfo, DeviceSelector};
+pub use error::DenoiseError;
+pub use guide::Guide;
+pub use handle::DenoiseHandle;
+pub use images::Options;
+pub use layout::{ChannelOrder, Layout};
+pub use noise::{estimate_buffer_noise, estimate_image_noise, estimate_linear_image_noise, estimate_linear_noise, estimate_noise, NoiseEstimate};
+/// Half precision sample type accepted by [`denoise`], re-exported so callers don't have to match the `half` version
+pub use half::f16;
+
+use std::str::FromStr;
 use std::sync::Arc;
 use bytemuck::Pod;
 use num_traits::Zero;
-use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
-use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, PrimaryCommandBuffer};
 use vulkano::device::{Device, Features, DeviceCreateInfo, Queue, QueueCreateInfo};
 use vulkano::device::DeviceExtensions;
 use vulkano::instance::{Instance, InstanceCreateInfo, InstanceExtensions};
 use vulkano::Version;
 use vulkano::device::physical::{PhysicalDevice, PhysicalDeviceType};
-use vulkano::image::{ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage};
-use vulkano::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, SamplerReductionMode};
-use vulkano::sync::GpuFuture;
 use clap::{Parser, ValueEnum};
 
-pub fn vlk_init() -> (Arc<Device>, Arc<Queue>) {
-        let instance =
-            Instance::new(InstanceCreateInfo { application_name: None, application_version: Version::V1_3, enabled_extensions: InstanceExtensions::none(),
-                enabled_layers: vec![], engine_name: None, engine_version: Default::default(), function_pointers: None, max_api_version: None, _ne: Default::default() })
-                .expect("failed to create instance");
+pub(crate) fn create_instance() -> Result<Arc<Instance>, DenoiseError> {
+    Ok(Instance::new(InstanceCreateInfo { application_name: None, application_version: Version::V1_3, enabled_extensions: InstanceExtensions::none(),
+        enabled_layers: vec![], engine_name: None, engine_version: Default::default(), function_pointers: None, max_api_version: None, _ne: Default::default() })?)
+}
 
-    let device_extensions = DeviceExtensions {
+pub(crate) fn required_device_extensions() -> DeviceExtensions {
+    DeviceExtensions {
         khr_storage_buffer_storage_class: true,
         khr_16bit_storage: true,
         ..DeviceExtensions::none()
-    };
+    }
+}
+
+pub fn vlk_init() -> Result<(Arc<Device>, Arc<Queue>), DenoiseError> {
+    vlk_init_with(&DeviceSelector::Best)
+}
+
+/// Initialises the physical device picked by `selector`, the most capable one when several match.
+///
+/// [`DeviceSelector::Name`] is the exception, it takes the first match in the order of [`list_devices`].
+pub fn vlk_init_with(selector: &DeviceSelector) -> Result<(Arc<Device>, Arc<Queue>), DenoiseError> {
+        let instance = create_instance()?;
 
     let (physical_device, queue_family) = PhysicalDevice::enumerate(&instance)
-        .filter(|&p| p.supported_extensions().is_superset_of(&device_extensions))
+        .filter(|p| selector.matches(p))
+        .filter(devices::is_supported)
         .filter_map(|p| {
             p.queue_families()
              .find(|&q| q.supports_compute())
              .map(|q| (p, q))
         })
-        .min_by_key(|(p, _)| match p.properties().device_type {
-            PhysicalDeviceType::DiscreteGpu => 0,
-            PhysicalDeviceType::IntegratedGpu => 1,
-            PhysicalDeviceType::VirtualGpu => 2,
-            PhysicalDeviceType::Cpu => 3,
-            PhysicalDeviceType::Other => 4,
+        .min_by_key(|(p, _)| match (selector, p.properties().device_type) {
+            //Every match ranks the same, so the first one wins
+            (DeviceSelector::Name(_), _) => 0,
+            (_, PhysicalDeviceType::DiscreteGpu) => 0,
+            (_, PhysicalDeviceType::IntegratedGpu) => 1,
+            (_, PhysicalDeviceType::VirtualGpu) => 2,
+            (_, PhysicalDeviceType::Cpu) => 3,
+            (_, PhysicalDeviceType::Other) => 4,
         })
-        .unwrap();
+        .ok_or_else(|| DenoiseError::NoSuitableDevice(format!("no {} supports storage buffers, 16-bit storage and compute", selector)))?;
 
         let devinfo = DeviceCreateInfo {
             enabled_extensions:  DeviceExtensions {
@@ -66,58 +97,461 @@ pub fn vlk_init() -> (Arc<Device>, Arc<Queue>) {
                 Device::new(
                     physical_device,
                         devinfo,
-                )
-                    .expect("failed to create device")
+                )?
         };
 
         //let limits = device.physical_device().limits();
 
 
-        let queue = queues.next().unwrap();
-        (device, queue)
+        let queue = queues.next().ok_or_else(|| DenoiseError::NoSuitableDevice("device has no compute queue".to_string()))?;
+        Ok((device, queue))
 }
 
-#[derive(Debug, Copy, Clone, ValueEnum, Parser)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum, Parser)]
 pub enum UsingShader {
     Fragment,
-    Compute
+    Compute,
+    ///Pure Rust implementation running on the CPU, doesn't need Vulkan
+    ///
+    ///Results match the GPU shaders within ±1 code value for integer samples and 1e-4 for floating point ones,
+    ///hardware bilinear filtering has less sub-texel precision
+    Cpu
 }
 
-#[derive(Debug, Copy, Clone, ValueEnum, Parser)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ValueEnum, Parser)]
 pub enum Algo {
     ///Smart denoise, reimplementation of https://github.com/BrutPitt/glslSmartDeNoise/
     Smart,
     ///Radial denoise. Better for thin lines like hairs, leaves, grass, etc
-    Radial
+    Radial,
+    ///Non-local means, averages pixels whose surroundings look alike. Better for textures with repeated patterns
+    NonLocalMeans
+}
+
+/// How the alpha channel of RGBA and grayscale+alpha images is treated.
+///
+/// Alpha never takes part in the edge-stopping weight, only colour differences do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, ValueEnum)]
+pub enum AlphaMode {
+    ///Alpha is copied unchanged
+    #[default]
+    Preserve,
+    ///Alpha is filtered with the same weights as the colour
+    Denoise,
+    ///Colour is premultiplied by alpha while filtering, alpha is copied unchanged
+    Premultiplied,
+    ///Neighbours are weighted by their alpha so transparent pixels don't contribute, alpha is copied unchanged
+    UseAsWeight
+}
+
+/// Where samples falling outside the image come from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, ValueEnum)]
+pub enum BorderMode {
+    ///Nearest edge pixel is repeated
+    #[default]
+    Clamp,
+    ///Image is mirrored at its edges
+    Mirror,
+    ///Opposite edge is used, the way the shaders always sampled before
+    Wrap,
+    ///Outside pixels are left out of the weighted average, as if the border never matched
+    Constant
+}
+
+/// Curve relating colour samples to light intensity.
+///
+/// Colour is decoded to linear light before filtering and encoded back afterwards, so `threshold` means the same
+/// in shadows and highlights. Alpha is always linear.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum TransferFunction {
+    ///Samples are filtered as they are
+    #[default]
+    Linear,
+    ///sRGB curve of IEC 61966-2-1
+    Srgb,
+    ///Camera curve of ITU-R BT.709
+    Rec709,
+    ///Pure power law, linear light is the normalised sample raised to this exponent
+    Gamma(f32)
+}
+
+impl TransferFunction {
+    /// Value of the `transfer` push constant, has to match the `TRANSFER_` defines of `templates/shader_params.mustache`.
+    fn shader_code(self) -> (u32, f32) {
+        match self {
+            TransferFunction::Linear => (0, 1.0),
+            TransferFunction::Srgb => (1, 1.0),
+            TransferFunction::Rec709 => (2, 1.0),
+            TransferFunction::Gamma(gamma) => (3, gamma),
+        }
+    }
+
+    /// Normalised sample to linear light, negative values are mirrored.
+    pub fn to_linear(self, v: f32) -> f32 {
+        let a = v.abs();
+        let linear = match self {
+            TransferFunction::Linear => a,
+            TransferFunction::Srgb if a <= 0.04045 => a / 12.92,
+            TransferFunction::Srgb => ((a + 0.055) / 1.055).powf(2.4),
+            TransferFunction::Rec709 if a < 0.081 => a / 4.5,
+            TransferFunction::Rec709 => ((a + 0.099) / 1.099).powf(1.0 / 0.45),
+            TransferFunction::Gamma(gamma) => a.powf(gamma),
+        };
+        linear.copysign(v)
+    }
+
+    /// Inverse of [`to_linear`](Self::to_linear).
+    pub fn from_linear(self, v: f32) -> f32 {
+        let a = v.abs();
+        let encoded = match self {
+            TransferFunction::Linear => a,
+            TransferFunction::Srgb if a <= 0.0031308 => a * 12.92,
+            TransferFunction::Srgb => 1.055 * a.powf(1.0 / 2.4) - 0.055,
+            TransferFunction::Rec709 if a < 0.018 => a * 4.5,
+            TransferFunction::Rec709 => 1.099 * a.powf(0.45) - 0.099,
+            TransferFunction::Gamma(gamma) => a.powf(1.0 / gamma),
+        };
+        encoded.copysign(v)
+    }
+}
+
+/// Parses `linear`, `srgb`, `rec709` or a gamma exponent such as `2.2`.
+impl FromStr for TransferFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(gamma) = s.parse::<f32>() {
+            return Ok(TransferFunction::Gamma(gamma));
+        }
+        match s {
+            "linear" => Ok(TransferFunction::Linear),
+            "srgb" => Ok(TransferFunction::Srgb),
+            "rec709" => Ok(TransferFunction::Rec709),
+            other => Err(format!("unknown transfer function \"{}\", expected linear, srgb, rec709 or a gamma exponent", other)),
+        }
+    }
+}
+
+/// Space colour images are filtered in, luma and chroma get their own sigma and threshold in all but [`ColorSpace::Rgb`].
+///
+/// Conversion follows the transfer function, so Lab and Oklab expect linear light: set one for encoded data.
+/// Grayscale images are always filtered as they are.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, ValueEnum)]
+pub enum ColorSpace {
+    ///Red, green and blue share one weight
+    #[default]
+    Rgb,
+    ///Rec.709 luma and blue/red differences
+    #[value(name = "ycbcr")]
+    YCbCr,
+    ///CIELab with D65 white, L, a and b divided by 100
+    Lab,
+    ///Oklab by Björn Ottosson
+    Oklab
+}
+
+/// Tuning of [`Algo::Radial`], the defaults are the values it always used.
+///
+/// The filter first looks for the direction around a pixel with the least difference on a coarse disc,
+/// then averages along it and blends the result with the original pixel depending on how uniform it was.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RadialParams {
+    /// Radius in pixels of the disc the best direction is searched on
+    pub small_radius: f32,
+    /// Exponents of hue and value differences while searching the direction with `use_hsv`
+    pub hsv_search_power: [f32; 2],
+    /// Exponents of hue and value differences while averaging with `use_hsv`
+    pub hsv_filter_power: [f32; 2],
+    /// Step between searched directions, in pixels along the perimeter
+    pub angle_step: f32,
+    /// Step between the directions tried from one coarse neighbour of the best direction to the other,
+    /// in pixels along the perimeter
+    pub refine_step: f32,
+    /// Curve from the smallest difference to the blend with the original pixel, smaller keeps more of the filtered one
+    pub fres_exponent: f32,
+    /// Curve from how much the values along the best direction vary to how much it's smoothed regardless of differences
+    pub disperse_exponent: f32,
+    /// Perimeter over this is the number of directions averaged on each side of the best one
+    pub direction_divisor: f32,
+}
+
+impl Default for RadialParams {
+    fn default() -> Self {
+        Self {
+            small_radius: 7.0,
+            hsv_search_power: [2.25, 0.75],
+            hsv_filter_power: [2.0, 0.75],
+            angle_step: 1.5,
+            refine_step: 0.5,
+            fres_exponent: 0.075,
+            disperse_exponent: 0.1,
+            direction_divisor: 20.0,
+        }
+    }
+}
+
+/// Parameters of [`Algo::NonLocalMeans`], which ignores sigma, kSigma and threshold.
+///
+/// Every pixel of the search window is weighted by how much the patch around it differs from the patch around
+/// the filtered pixel, so the cost grows with both areas.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NonLocalMeansParams {
+    /// Patches are `2 * patch_radius + 1` pixels wide
+    pub patch_radius: u32,
+    /// Pixels up to this far in each direction are averaged
+    pub search_radius: u32,
+    /// Root mean square patch difference, relative to the white level, at which a weight has dropped to 1/e.
+    /// Usually a bit above the noise standard deviation
+    pub strength: f32,
+}
+
+impl Default for NonLocalMeansParams {
+    fn default() -> Self {
+        Self { patch_radius: 1, search_radius: 7, strength: 0.05 }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub struct DenoiseParams {
     sigma: f32,
     kSigma: f32,
-    threshold: f32
+    threshold: f32,
+    alpha_mode: AlphaMode,
+    border_mode: BorderMode,
+    /// Sample value of full intensity, [`Denoiseable::MAX_VALUE`] of the sample type when not set
+    white_level: Option<f32>,
+    transfer_function: TransferFunction,
+    color_space: ColorSpace,
+    /// Sigma and threshold of the chroma channels, same as the luma ones when not set
+    chroma: Option<(f32, f32)>,
+    radial: RadialParams,
+    non_local_means: NonLocalMeansParams
 }
+
+/// Push constants of the shaders, has to match `templates/shader_params.mustache`.
+///
+/// Vulkan only guarantees 128 bytes of push constants, this takes 124.
+#[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct ShaderParams {
     Width: u32,
     Height: u32,
     sigma: f32,
     kSigma: f32,
-    threshold: f32
+    threshold: f32,
+    alpha_mode: u32,
+    white_level: f32,
+    border_mode: u32,
+    /// Position of the first output pixel in the input image
+    offset_x: u32,
+    offset_y: u32,
+    /// Part of the input image inside the original one, in pixels
+    inside_x0: f32,
+    inside_y0: f32,
+    inside_x1: f32,
+    inside_y1: f32,
+    /// Transfer function of the colour samples and its exponent for [`TransferFunction::Gamma`]
+    transfer: u32,
+    gamma: f32,
+    /// Chroma sigma and threshold of the luma/chroma variants
+    chroma_sigma: f32,
+    chroma_threshold: f32,
+    /// Fields of [`RadialParams`]
+    small_radius: f32,
+    search_power_hue: f32,
+    search_power_value: f32,
+    filter_power_hue: f32,
+    filter_power_value: f32,
+    angle_step: f32,
+    refine_step: f32,
+    fres_exponent: f32,
+    disperse_exponent: f32,
+    direction_divisor: f32,
+    /// Fields of [`NonLocalMeansParams`]
+    patch_radius: u32,
+    search_radius: u32,
+    strength: f32
 }
 
 impl ShaderParams {
     pub fn new(Width: u32, Height: u32, denoise_parameters: DenoiseParams) -> Self {
+        let (transfer, gamma) = denoise_parameters.transfer_function.shader_code();
+        let (chroma_sigma, chroma_threshold) = denoise_parameters.chroma();
+        let radial = denoise_parameters.radial;
+        let non_local_means = denoise_parameters.non_local_means;
         Self { Width, Height,
             sigma: denoise_parameters.sigma,
             kSigma: denoise_parameters.kSigma,
-            threshold: denoise_parameters.threshold }
+            threshold: denoise_parameters.threshold,
+            alpha_mode: denoise_parameters.alpha_mode as u32,
+            white_level: denoise_parameters.white_level.unwrap_or(1.0),
+            border_mode: denoise_parameters.border_mode as u32,
+            offset_x: 0,
+            offset_y: 0,
+            inside_x0: 0.0,
+            inside_y0: 0.0,
+            inside_x1: Width as f32,
+            inside_y1: Height as f32,
+            transfer,
+            gamma,
+            chroma_sigma,
+            chroma_threshold,
+            small_radius: radial.small_radius,
+            search_power_hue: radial.hsv_search_power[0],
+            search_power_value: radial.hsv_search_power[1],
+            filter_power_hue: radial.hsv_filter_power[0],
+            filter_power_value: radial.hsv_filter_power[1],
+            angle_step: radial.angle_step,
+            refine_step: radial.refine_step,
+            fres_exponent: radial.fres_exponent,
+            disperse_exponent: radial.disperse_exponent,
+            direction_divisor: radial.direction_divisor,
+            patch_radius: non_local_means.patch_radius,
+            search_radius: non_local_means.search_radius,
+            strength: non_local_means.strength }
+    }
+
+    /// Output is a tile starting at `offset` of the input, which covers `inside` of the whole image.
+    pub(crate) fn with_tile(self, offset: [u32; 2], inside: [f32; 4]) -> Self {
+        Self {
+            offset_x: offset[0],
+            offset_y: offset[1],
+            inside_x0: inside[0],
+            inside_y0: inside[1],
+            inside_x1: inside[2],
+            inside_y1: inside[3],
+            ..self
+        }
     }
 }
 
 impl DenoiseParams {
     pub fn new(sigma: f32, kSigma: f32, threshold: f32) -> Self {
-        Self { sigma, kSigma, threshold }
+        Self { sigma, kSigma, threshold, alpha_mode: AlphaMode::default(), border_mode: BorderMode::default(), white_level: None,
+               transfer_function: TransferFunction::default(), color_space: ColorSpace::default(), chroma: None,
+               radial: RadialParams::default(), non_local_means: NonLocalMeansParams::default() }
+    }
+
+    pub fn sigma(&self) -> f32 {
+        self.sigma
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    pub fn non_local_means(&self) -> NonLocalMeansParams {
+        self.non_local_means
+    }
+
+    pub fn with_alpha_mode(self, alpha_mode: AlphaMode) -> Self {
+        Self { alpha_mode, ..self }
+    }
+
+    pub fn with_border_mode(self, border_mode: BorderMode) -> Self {
+        Self { border_mode, ..self }
+    }
+
+    /// Samples are divided by `white_level` before filtering, so `threshold` means the same for every bit depth.
+    ///
+    /// Use it for data not spanning the whole sample type, e.g. 1023 for 10-bit or 4095 for 12-bit samples stored in `u16`.
+    pub fn with_white_level(self, white_level: f32) -> Self {
+        Self { white_level: Some(white_level), ..self }
+    }
+
+    /// Colour is converted from `transfer_function` to linear light for filtering and back, after dividing by the white level.
+    pub fn with_transfer_function(self, transfer_function: TransferFunction) -> Self {
+        Self { transfer_function, ..self }
+    }
+
+    /// Colour images are filtered in `color_space` instead of RGB, can't be combined with `use_hsv`.
+    pub fn with_color_space(self, color_space: ColorSpace) -> Self {
+        Self { color_space, ..self }
+    }
+
+    /// Sigma and threshold of the chroma channels in luma/chroma colour spaces, camera noise usually wants larger ones than luma.
+    pub fn with_chroma(self, sigma: f32, threshold: f32) -> Self {
+        Self { chroma: Some((sigma, threshold)), ..self }
+    }
+
+    /// Tuning of [`Algo::Radial`], the other algorithms ignore it.
+    pub fn with_radial(self, radial: RadialParams) -> Self {
+        Self { radial, ..self }
+    }
+
+    /// Parameters of [`Algo::NonLocalMeans`], the other algorithms ignore them.
+    pub fn with_non_local_means(self, non_local_means: NonLocalMeansParams) -> Self {
+        Self { non_local_means, ..self }
+    }
+
+    /// Fills in the white level of `D` unless one is set and checks it's usable.
+    pub(crate) fn resolve<D: Denoiseable>(self) -> Result<Self, DenoiseError> {
+        let white_level = self.white_level.unwrap_or(D::MAX_VALUE);
+        if !white_level.is_finite() || white_level <= 0.0 {
+            return Err(DenoiseError::InvalidParameter(format!("white level has to be positive, got {}", white_level)));
+        }
+        //Steps of zero would never end the shader loops
+        let radial = self.radial;
+        let positive = [radial.small_radius, radial.angle_step, radial.refine_step, radial.fres_exponent, radial.disperse_exponent, radial.direction_divisor];
+        if positive.iter().chain(&radial.hsv_search_power).chain(&radial.hsv_filter_power).any(|v| !v.is_finite() || *v <= 0.0) {
+            return Err(DenoiseError::InvalidParameter(format!("radial parameters have to be positive, got {:?}", radial)));
+        }
+        let strength = self.non_local_means.strength;
+        if !strength.is_finite() || strength <= 0.0 {
+            return Err(DenoiseError::InvalidParameter(format!("non-local means strength has to be positive, got {}", strength)));
+        }
+        if let TransferFunction::Gamma(gamma) = self.transfer_function {
+            if !gamma.is_finite() || gamma <= 0.0 {
+                return Err(DenoiseError::InvalidParameter(format!("gamma has to be positive, got {}", gamma)));
+            }
+        }
+        Ok(Self { white_level: Some(white_level), ..self })
+    }
+
+    pub(crate) fn white_level(&self) -> f32 {
+        self.white_level.unwrap_or(1.0)
+    }
+
+    pub(crate) fn border_mode(&self) -> BorderMode {
+        self.border_mode
+    }
+
+    pub(crate) fn transfer_function(&self) -> TransferFunction {
+        self.transfer_function
+    }
+
+    pub(crate) fn radial(&self) -> RadialParams {
+        self.radial
+    }
+
+    pub(crate) fn chroma(&self) -> (f32, f32) {
+        self.chroma.unwrap_or((self.sigma, self.threshold))
+    }
+
+    /// Colour space an image of `num_samples` is filtered in, grayscale ones always stay as they are.
+    ///
+    /// Guided filtering takes no colour differences, so it stays in RGB too and has no HSV variant.
+    pub(crate) fn filter_space(&self, use_hsv: bool, guided: bool, num_samples: usize) -> Result<ColorSpace, DenoiseError> {
+        match (num_samples, use_hsv, guided, self.color_space) {
+            (1 | 2, _, _, _) => Ok(ColorSpace::Rgb),
+            (_, true, true, _) => Err(DenoiseError::InvalidParameter("HSV filtering can't be combined with guided filtering".to_string())),
+            (_, _, _, ColorSpace::Rgb) | (_, false, false, _) => Ok(self.color_space),
+            (_, true, _, color_space) => Err(DenoiseError::InvalidParameter(format!("HSV filtering can't be combined with {:?}", color_space))),
+            (_, _, true, color_space) => Err(DenoiseError::InvalidParameter(format!("guided filtering can't be combined with {:?}", color_space))),
+        }
+    }
+
+    /// Pixels around an output pixel the filter may read, plus one for linear filtering and one spare.
+    pub(crate) fn halo(&self, algo: Algo) -> u32 {
+        let radius = (self.kSigma * self.sigma.max(self.chroma().0)).round().max(0.0) as u32;
+        let reach = match algo {
+            Algo::Smart => radius,
+            //Dispersion is estimated on a disc of its own
+            Algo::Radial => radius.max(self.radial.small_radius.ceil() as u32),
+            //Patches around the farthest searched pixels
+            Algo::NonLocalMeans => self.non_local_means.search_radius + self.non_local_means.patch_radius,
+        };
+        reach + 2
     }
 }
 
@@ -126,223 +560,158 @@ impl Default for DenoiseParams {
         Self {
             sigma: 7.0,
             kSigma: 3.0,
-            threshold: 0.195
+            threshold: 0.195,
+            alpha_mode: AlphaMode::default(),
+            border_mode: BorderMode::default(),
+            white_level: None,
+            transfer_function: TransferFunction::default(),
+            color_space: ColorSpace::default(),
+            chroma: None,
+            radial: RadialParams::default(),
+            non_local_means: NonLocalMeansParams::default()
         }
     }
 }
 
 pub trait TypeToFormat {
-    fn type2result_format(num_samples: usize) -> vulkano::format::Format;
-    fn type2sampled_format(num_samples: usize) -> vulkano::format::Format;
+    fn type2result_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError>;
+    fn type2sampled_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError>;
 }
 
 impl TypeToFormat for f32 {
-    fn type2result_format(num_samples: usize) -> vulkano::format::Format {
+    fn type2result_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
+        match num_samples {
+            1 => Ok(vulkano::format::Format::R32_SFLOAT),
+            2 => Ok(vulkano::format::Format::R32G32_SFLOAT),
+            3 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            4 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "f32" }),
+        }
+    }
+
+    fn type2sampled_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
+        match num_samples {
+            1 => Ok(vulkano::format::Format::R32_SFLOAT),
+            2 => Ok(vulkano::format::Format::R32G32_SFLOAT),
+            3 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            4 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "f32" }),
+        }
+    }
+}
+impl TypeToFormat for f16 {
+    fn type2result_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
         match num_samples {
-            1 => vulkano::format::Format::R32_SFLOAT,
-            3 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            4 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            _ => unimplemented!("Implemented only for single channel floating point."),
+            1 => Ok(vulkano::format::Format::R16_SFLOAT),
+            2 => Ok(vulkano::format::Format::R16G16_SFLOAT),
+            3 => Ok(vulkano::format::Format::R16G16B16A16_SFLOAT),
+            4 => Ok(vulkano::format::Format::R16G16B16A16_SFLOAT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "f16" }),
         }
     }
 
-    fn type2sampled_format(num_samples: usize) -> vulkano::format::Format {
+    fn type2sampled_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
         match num_samples {
-            1 => vulkano::format::Format::R32_SFLOAT,
-            3 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            4 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            _ => unimplemented!("Implemented only for single channel floating point."),
+            1 => Ok(vulkano::format::Format::R32_SFLOAT),
+            2 => Ok(vulkano::format::Format::R32G32_SFLOAT),
+            3 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            4 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "f16" }),
         }
     }
 }
 impl TypeToFormat for u8 {
-    fn type2result_format(num_samples: usize) -> vulkano::format::Format {
+    fn type2result_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
         match num_samples {
-            1 => vulkano::format::Format::R8_UINT,
-            3 => vulkano::format::Format::R8G8B8A8_UINT,
-            4 => vulkano::format::Format::R8G8B8A8_UINT,
-            _ => unimplemented!("Implemented only for 1 & 4 channel u8."),
+            1 => Ok(vulkano::format::Format::R8_UINT),
+            2 => Ok(vulkano::format::Format::R8G8_UINT),
+            3 => Ok(vulkano::format::Format::R8G8B8A8_UINT),
+            4 => Ok(vulkano::format::Format::R8G8B8A8_UINT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "u8" }),
         }
     }
 
-    fn type2sampled_format(num_samples: usize) -> vulkano::format::Format {
+    fn type2sampled_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
         match num_samples {
-            1 => vulkano::format::Format::R32_SFLOAT,
-            3 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            4 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            _ => unimplemented!("Implemented only for 1 & 4 channel u8."),
+            1 => Ok(vulkano::format::Format::R32_SFLOAT),
+            2 => Ok(vulkano::format::Format::R32G32_SFLOAT),
+            3 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            4 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "u8" }),
         }
     }
 }
 impl TypeToFormat for u16 {
-    fn type2result_format(num_samples: usize) -> vulkano::format::Format {
+    fn type2result_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
         match num_samples {
-            1 => vulkano::format::Format::R16_UINT,
-            3 => vulkano::format::Format::R16G16B16A16_UINT,
-            4 => vulkano::format::Format::R16G16B16A16_UINT,
-            _ => unimplemented!("Implemented only for 1 & 4 channel u16."),
+            1 => Ok(vulkano::format::Format::R16_UINT),
+            2 => Ok(vulkano::format::Format::R16G16_UINT),
+            3 => Ok(vulkano::format::Format::R16G16B16A16_UINT),
+            4 => Ok(vulkano::format::Format::R16G16B16A16_UINT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "u16" }),
         }
     }
 
-    fn type2sampled_format(num_samples: usize) -> vulkano::format::Format {
+    fn type2sampled_format(num_samples: usize) -> Result<vulkano::format::Format, DenoiseError> {
         match num_samples {
-            1 => vulkano::format::Format::R32_SFLOAT,
-            3 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            4 => vulkano::format::Format::R32G32B32A32_SFLOAT,
-            _ => unimplemented!("Implemented only for 1 & 4 channel u8."),
+            1 => Ok(vulkano::format::Format::R32_SFLOAT),
+            2 => Ok(vulkano::format::Format::R32G32_SFLOAT),
+            3 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            4 => Ok(vulkano::format::Format::R32G32B32A32_SFLOAT),
+            channels => Err(DenoiseError::UnsupportedChannelLayout { channels, sample_type: "u16" }),
         }
     }
 }
 
-pub trait Denoiseable: TypeToFormat + num_traits::AsPrimitive<f32> + Sized + Copy + Zero + Send + Sync + Pod {}
-impl Denoiseable for u8 {}
-impl Denoiseable for u16 {}
-impl Denoiseable for f32 {}
+pub trait Denoiseable: TypeToFormat + num_traits::AsPrimitive<f32> + Sized + Copy + Zero + Send + Sync + Pod {
+    /// Full scale sample value, the default white level
+    const MAX_VALUE: f32;
+    /// Converts filtered value back to a sample, integers are rounded and clamped to the type range
+    fn from_f32(v: f32) -> Self;
+}
+impl Denoiseable for u8 {
+    const MAX_VALUE: f32 = 255.0;
+    fn from_f32(v: f32) -> Self {
+        v.round().clamp(0.0, Self::MAX_VALUE) as u8
+    }
+}
+impl Denoiseable for u16 {
+    const MAX_VALUE: f32 = 65535.0;
+    fn from_f32(v: f32) -> Self {
+        v.round().clamp(0.0, Self::MAX_VALUE) as u16
+    }
+}
+impl Denoiseable for f32 {
+    const MAX_VALUE: f32 = 1.0; //Assuming data is already normalized
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+}
+impl Denoiseable for f16 {
+    const MAX_VALUE: f32 = 1.0; //Same as f32, HDR values above 1.0 are kept as is
+    fn from_f32(v: f32) -> Self {
+        f16::from_f32(v)
+    }
+}
 
-pub fn denoise<D>(buf: &[D], img_w: u32, img_h: u32, shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo) -> Vec<D>
+/// Denoises `buf` holding `img_w` x `img_h` pixels of 1, 2, 3 or 4 interleaved channels.
+///
+/// Initialises Vulkan for this single call, use [`Denoiser`] to process many images.
+/// Falls back to the CPU when there's no suitable device, [`UsingShader::Cpu`] doesn't touch Vulkan at all.
+/// Use [`Denoiser::with_policy`] with [`BackendPolicy::RequireGpu`] to fail instead of falling back.
+pub fn try_denoise<D>(buf: &[D], img_w: u32, img_h: u32, shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo) -> Result<Vec<D>, DenoiseError>
 where D: Denoiseable
 {
-    let (device, queue) = vlk_init();
-
-    let num_input_samples = buf.len() / (img_w * img_h) as usize;
-
-    let num_output_samples = match num_input_samples {
-        3 => 4,
-        _ => num_input_samples
-    };
-
-    let input_usage = BufferUsage{
-        transfer_source: true,
-        transfer_destination: false,
-        uniform_texel_buffer: false,
-        storage_texel_buffer: false,
-        uniform_buffer: false,
-        storage_buffer: true,
-        index_buffer: false,
-        vertex_buffer: false,
-        indirect_buffer: false,
-        device_address: true,
-        _ne: Default::default()
-    };
-
-    let input2sample: Vec<f32> = match num_input_samples {
-        3 => buf.iter()
-                .enumerate()
-                .flat_map(|(i, x)| {
-                    let rs: f32 = (*x).as_();
-                    if i%3==2 {vec![rs, 0.0]} //Adds alpha channel for each 3rd item
-                    else {vec![rs]}
-                })
-                .collect(),
-        _ => buf.iter()
-                .map(|x| {
-                    let rs: f32 = (*x).as_();
-                    rs
-                })
-                .collect()
+    let policy = match shader_type {
+        UsingShader::Cpu => BackendPolicy::CpuOnly,
+        _ => BackendPolicy::PreferGpu
     };
+    Denoiser::with_policy(policy)?.denoise(buf, img_w, img_h, shader_type, params, use_hsv, algo)
+}
 
-    let img_buf: Arc<CpuAccessibleBuffer<[f32]>> =
-            CpuAccessibleBuffer::from_iter(device.clone(), input_usage, false,
-                                           input2sample.into_iter()).expect("failed to create buffer");
-
-    #[cfg(debug_assertions)] dbg!(D::type2sampled_format(num_input_samples));
-    let input_img = StorageImage::with_usage(device.clone(),
-                                             ImageDimensions::Dim2d { width: img_w, height: img_h, array_layers: 1},
-                                             D::type2sampled_format(num_input_samples),
-                                             ImageUsage {
-                                                 transfer_source: false,
-                                                 transfer_destination: true,
-                                                 sampled: true,
-                                                 storage: true,
-                                                 color_attachment: false,
-                                                 depth_stencil_attachment: false,
-                                                 transient_attachment: false,
-                                                 input_attachment: false
-                                             },
-                                             ImageCreateFlags::none(),
-                                             Some(queue.family())).unwrap();
-
-
-    //Buffer to image
-    let command_buffer = {
-        let mut builder =
-            AutoCommandBufferBuilder::primary(device.clone(), queue.family(), CommandBufferUsage::OneTimeSubmit).unwrap();
-        builder
-            .copy_buffer_to_image(img_buf.clone(), input_img.clone()).unwrap();
-        builder.build().unwrap()
-    };
-    #[cfg(debug_assertions)] eprintln!("Execute copy buffer to image");
-    let finished = command_buffer.execute(queue.clone()).unwrap();
-    finished.then_signal_fence_and_flush().unwrap()
-            .wait(None).unwrap();
-
-    let sampler = Sampler::new(device.clone(), SamplerCreateInfo {
-        mag_filter: Filter::Linear,
-        min_filter: Filter::Linear,
-        mipmap_mode: SamplerMipmapMode::Linear,
-        reduction_mode: SamplerReductionMode::WeightedAverage,
-        address_mode: [SamplerAddressMode::Repeat; 3],
-        mip_lod_bias: 0.0,
-        lod: 0.0..=0.0,
-        anisotropy: None,
-        //anisotropy: Some(4.0),
-        ..Default::default()
-    }).unwrap();
-
-    let result_img = StorageImage::with_usage(device.clone(),
-                                              ImageDimensions::Dim2d { width: img_w, height: img_h, array_layers: 1},
-                                              D::type2result_format(num_output_samples),
-                                              ImageUsage {
-                                                  transfer_source: true,
-                                                  transfer_destination: false,
-                                                  sampled: false,
-                                                  storage: true,
-                                                  color_attachment: true,
-                                                  depth_stencil_attachment: false,
-                                                  transient_attachment: false,
-                                                  input_attachment: false
-                                              },
-                                              ImageCreateFlags::none(),
-                                              Some(queue.family())).unwrap();
-
-    match shader_type {
-        UsingShader::Fragment => denoise_frag::denoise(device.clone(), queue.clone(), input_img,
-                                                       result_img.clone(), sampler, params, use_hsv, algo),
-        UsingShader::Compute => denoise_compute::denoise(device.clone(), queue.clone(), input_img,
-                                                         result_img.clone(), sampler, params, use_hsv, algo)
-    }
-
-    let result_buf = CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage{
-        transfer_source: false,
-        transfer_destination: true,
-        uniform_texel_buffer: false,
-        storage_texel_buffer: false,
-        uniform_buffer: true,
-        storage_buffer: true,
-        index_buffer: false,
-        vertex_buffer: false,
-        indirect_buffer: false,
-        device_address: true,
-        _ne: Default::default()
-    }, false, (0..img_w*img_h*num_output_samples as u32).map(|_| D::zero())).expect("failed to create output buffer");
-
-    //Read filtered image
-    let command_buffer = {
-        let mut builder =
-            AutoCommandBufferBuilder::primary(device, queue.family(), CommandBufferUsage::OneTimeSubmit).unwrap();
-        builder
-            .copy_image_to_buffer(result_img, result_buf.clone()).unwrap();
-        builder.build().unwrap()
-    };
-    #[cfg(debug_assertions)] eprintln!("Execute copy image to buffer");
-    let finished = command_buffer.execute(queue.clone()).unwrap();
-    finished.then_signal_fence_and_flush().unwrap()
-            .wait(None).unwrap();
-
-    let rl = result_buf.read().unwrap();
-    match num_input_samples {
-        3 => rl.chunks(4).into_iter().flat_map(|v| vec![v[0],v[1],v[2]]).collect(),
-        _ => rl.to_vec()
-    }
+/// Same as [`try_denoise`].
+pub fn denoise<D>(buf: &[D], img_w: u32, img_h: u32, shader_type: UsingShader, params: DenoiseParams, use_hsv: bool, algo: Algo) -> Result<Vec<D>, DenoiseError>
+where D: Denoiseable
+{
+    try_denoise(buf, img_w, img_h, shader_type, params, use_hsv, algo)
 }
@@ -0,0 +1,167 @@
+use std::fmt;
+use image::{DynamicImage, ImageBuffer, Pixel};
+use crate::{DenoiseError, DenoiseParams, Denoiseable, NonLocalMeansParams, TransferFunction};
+
+/// Median absolute deviation of Gaussian noise over its standard deviation
+const MAD_TO_SIGMA: f32 = 0.6745;
+/// Sigma for noiseless images and its growth with the relative noise level
+const BASE_SIGMA: f32 = 2.0;
+const SIGMA_PER_NOISE: f32 = 50.0;
+const MAX_SIGMA: f32 = 10.0;
+/// Non-local means strength over the noise level
+const STRENGTH_PER_NOISE: f32 = 1.5;
+
+/// Standard deviation of the noise in every channel of an image, see [`estimate_noise`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseEstimate {
+    /// Per channel, in sample values
+    pub sigma: Vec<f32>,
+    /// Sample value the parameters are relative to, full scale of the sample type unless set
+    pub white_level: f32,
+}
+
+impl NoiseEstimate {
+    /// Same estimate for samples whose full intensity is `white_level`, e.g. 1023 for 10-bit data in `u16`.
+    pub fn with_white_level(self, white_level: f32) -> Self {
+        Self { white_level, ..self }
+    }
+
+    /// Noise of every channel as a fraction of the white level, the unit `threshold` is given in.
+    pub fn relative(&self) -> Vec<f32> {
+        self.sigma.iter().map(|s| s / self.white_level).collect()
+    }
+
+    /// Sigma, threshold and non-local means strength for the estimated noise, alpha of 2 and 4 channel images doesn't count.
+    ///
+    /// Threshold is the spread of the difference between two noisy pixels, so noise falls inside it and edges above it.
+    /// Sigma grows with the noise so stronger noise is averaged over more pixels.
+    pub fn params(&self) -> DenoiseParams {
+        let relative = self.relative();
+        let colour = match relative.len() {
+            2 => &relative[..1],
+            4 => &relative[..3],
+            _ => &relative[..],
+        };
+        let noise = colour.iter().sum::<f32>() / colour.len() as f32;
+        let threshold = noise * (2.0 * colour.len() as f32).sqrt();
+        let sigma = (BASE_SIGMA + noise * SIGMA_PER_NOISE).min(MAX_SIGMA);
+        let non_local_means = NonLocalMeansParams { strength: (noise * STRENGTH_PER_NOISE).max(1e-3), ..NonLocalMeansParams::default() };
+        DenoiseParams::new(sigma, DenoiseParams::default().kSigma, threshold.max(1e-3))
+            .with_white_level(self.white_level)
+            .with_non_local_means(non_local_means)
+    }
+}
+
+impl fmt::Display for NoiseEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "noise sigma")?;
+        for (sigma, relative) in self.sigma.iter().zip(self.relative()) {
+            write!(f, " {:.4} ({:.2}%)", sigma, relative * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Estimates the noise of `buf` holding `img_w` x `img_h` pixels of interleaved channels.
+///
+/// Uses the median absolute deviation of the finest diagonal Haar wavelet coefficients, which edges and smooth gradients
+/// barely reach, so it holds for natural images and not only flat ones.
+pub fn estimate_noise<D>(buf: &[D], img_w: u32, img_h: u32) -> Result<NoiseEstimate, DenoiseError>
+where D: Denoiseable
+{
+    let (w, h) = (img_w as usize, img_h as usize);
+    if w * h == 0 || !buf.len().is_multiple_of(w * h) {
+        return Err(DenoiseError::BufferSizeMismatch { width: img_w, height: img_h, len: buf.len() });
+    }
+    if w < 2 || h < 2 {
+        return Err(DenoiseError::InvalidParameter(format!("noise can't be estimated on a {}x{} image", img_w, img_h)));
+    }
+    let channels = buf.len() / (w * h);
+    let sample = |x: usize, y: usize, c: usize| -> f32 { buf[(y * w + x) * channels + c].as_() };
+
+    let sigma = (0..channels).map(|c| {
+        //Diagonal coefficient of every 2x2 block, it has the noise variance for independent noise
+        let mut coefficients: Vec<f32> = (0..h / 2).flat_map(|by| (0..w / 2).map(move |bx| (bx * 2, by * 2)))
+            .map(|(x, y)| ((sample(x, y, c) - sample(x + 1, y, c) - sample(x, y + 1, c) + sample(x + 1, y + 1, c)) / 2.0).abs())
+            .collect();
+        let middle = coefficients.len() / 2;
+        let (_, median, _) = coefficients.select_nth_unstable_by(middle, f32::total_cmp);
+        *median / MAD_TO_SIGMA
+    }).collect();
+    Ok(NoiseEstimate { sigma, white_level: D::MAX_VALUE })
+}
+
+/// [`estimate_noise`] in the linear light `transfer_function` decodes the colour samples of `buf` to, which is what
+/// the filters compare once it's given to [`DenoiseParams::with_transfer_function`].
+///
+/// The curves stretch shadows and squeeze highlights, so noise measured in code values gives the wrong threshold there.
+/// `white_level` is the sample value of full intensity, full scale of the sample type when `None`. Sigma comes back
+/// in sample values scaled by it, so [`NoiseEstimate::params`] fits the encoded buffer.
+pub fn estimate_linear_noise<D>(buf: &[D], img_w: u32, img_h: u32, transfer_function: TransferFunction, white_level: Option<f32>)
+    -> Result<NoiseEstimate, DenoiseError>
+where D: Denoiseable
+{
+    let num_pixels = img_w as usize * img_h as usize;
+    if num_pixels == 0 || !buf.len().is_multiple_of(num_pixels) {
+        return Err(DenoiseError::BufferSizeMismatch { width: img_w, height: img_h, len: buf.len() });
+    }
+    let channels = buf.len() / num_pixels;
+    let white_level = white_level.unwrap_or(D::MAX_VALUE);
+    let linear: Vec<f32> = buf.iter().enumerate()
+        .map(|(i, v)| {
+            let v: f32 = v.as_() / white_level;
+            //Alpha (second or fourth sample) isn't encoded
+            match (channels, i % channels) {
+                (2, 1) | (4, 3) => v,
+                _ => transfer_function.to_linear(v),
+            }
+        })
+        .collect();
+    let estimate = estimate_noise(&linear, img_w, img_h)?;
+    Ok(NoiseEstimate { sigma: estimate.sigma.iter().map(|s| s * white_level).collect(), white_level })
+}
+
+/// [`estimate_noise`] of any `image` crate buffer of 8-bit, 16-bit or `f32` samples.
+pub fn estimate_buffer_noise<P, C>(img: &ImageBuffer<P, C>) -> Result<NoiseEstimate, DenoiseError>
+where P: Pixel,
+      P::Subpixel: Denoiseable,
+      C: std::ops::Deref<Target = [P::Subpixel]>
+{
+    estimate_noise(img.as_raw(), img.width(), img.height())
+}
+
+/// [`estimate_noise`] of a [`DynamicImage`], variants the denoiser converts are estimated as `Rgba32F`.
+pub fn estimate_image_noise(img: &DynamicImage) -> Result<NoiseEstimate, DenoiseError> {
+    match img {
+        DynamicImage::ImageLuma8(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageLumaA8(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageRgb8(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageRgba8(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageLuma16(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageLumaA16(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageRgb16(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageRgba16(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageRgb32F(img) => estimate_buffer_noise(img),
+        DynamicImage::ImageRgba32F(img) => estimate_buffer_noise(img),
+        img => estimate_buffer_noise(&img.to_rgba32f()),
+    }
+}
+
+/// [`estimate_linear_noise`] of a [`DynamicImage`], variants the denoiser converts are estimated as `Rgba32F`.
+pub fn estimate_linear_image_noise(img: &DynamicImage, transfer_function: TransferFunction, white_level: Option<f32>)
+    -> Result<NoiseEstimate, DenoiseError> {
+    let (w, h) = (img.width(), img.height());
+    match img {
+        DynamicImage::ImageLuma8(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageLumaA8(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageRgb8(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageRgba8(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageLuma16(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageLumaA16(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageRgb16(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageRgba16(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageRgb32F(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        DynamicImage::ImageRgba32F(img) => estimate_linear_noise(img.as_raw(), w, h, transfer_function, white_level),
+        img => estimate_linear_noise(img.to_rgba32f().as_raw(), w, h, transfer_function, white_level),
+    }
+}
@@ -9,7 +9,7 @@ pub fn generate_shaders() {
         let mut data = HashMap::new();
         //8-bit grayscale
         data.insert("output_format", "r8ui");
-        data.insert("max_val", "255");
+        data.insert("type_max", "255");
         data.insert("swizzle_vec", "r");
         data.insert("processing_t", "float");
         data.insert("output_t", "uvec4");
@@ -21,7 +21,7 @@ pub fn generate_shaders() {
         let mut data = HashMap::new();
         //16-bit grayscale
         data.insert("output_format", "r16ui");
-        data.insert("max_val", "255");
+        data.insert("type_max", "65535");
         data.insert("swizzle_vec", "r");
         data.insert("processing_t", "float");
         data.insert("output_t", "uvec4");
@@ -29,13 +29,40 @@ pub fn generate_shaders() {
         datas.push(data);
     }
 
+    {
+        let mut data = HashMap::new();
+        //8-bit grayscale with alpha
+        data.insert("output_format", "rg8ui");
+        data.insert("type_max", "255");
+        data.insert("swizzle_vec", "r");
+        data.insert("alpha_swizzle", "g");
+        data.insert("processing_t", "float");
+        data.insert("output_t", "uvec4");
+        data.insert("is_int_type", "true");
+        datas.push(data);
+    }
+
+    {
+        let mut data = HashMap::new();
+        //16-bit grayscale with alpha
+        data.insert("output_format", "rg16ui");
+        data.insert("type_max", "65535");
+        data.insert("swizzle_vec", "r");
+        data.insert("alpha_swizzle", "g");
+        data.insert("processing_t", "float");
+        data.insert("output_t", "uvec4");
+        data.insert("is_int_type", "true");
+        datas.push(data);
+    }
+
     {
         let mut data = HashMap::new();
         //8-bit rgba
         data.insert("output_format", "rgba8ui");
-        data.insert("max_val", "255");
-        data.insert("swizzle_vec", "rgba");
-        data.insert("processing_t", "vec4");
+        data.insert("type_max", "255");
+        data.insert("swizzle_vec", "rgb");
+        data.insert("alpha_swizzle", "a");
+        data.insert("processing_t", "vec3");
         data.insert("output_t", "uvec4");
         data.insert("is_int_type", "true");
         data.insert("is_vector_type", "true");
@@ -47,9 +74,10 @@ pub fn generate_shaders() {
         let mut data = HashMap::new();
         //16-bit rgba
         data.insert("output_format", "rgba16ui");
-        data.insert("max_val", "65535");
-        data.insert("swizzle_vec", "rgba");
-        data.insert("processing_t", "vec4");
+        data.insert("type_max", "65535");
+        data.insert("swizzle_vec", "rgb");
+        data.insert("alpha_swizzle", "a");
+        data.insert("processing_t", "vec3");
         data.insert("output_t", "uvec4");
         data.insert("is_int_type", "true");
         data.insert("is_vector_type", "true");
@@ -60,13 +88,68 @@ pub fn generate_shaders() {
         let mut data = HashMap::new();
         //32-bit float
         data.insert("output_format", "r32f");
-        //data.insert("max_val", "1.0"); //Assuming data is already normalized
         data.insert("swizzle_vec", "r");
         data.insert("processing_t", "float");
         data.insert("output_t", "vec4");
         datas.push(data);
     }
 
+    {
+        let mut data = HashMap::new();
+        //16-bit float
+        data.insert("output_format", "r16f");
+        data.insert("swizzle_vec", "r");
+        data.insert("processing_t", "float");
+        data.insert("output_t", "vec4");
+        datas.push(data);
+    }
+
+    {
+        let mut data = HashMap::new();
+        //32-bit float grayscale with alpha
+        data.insert("output_format", "rg32f");
+        data.insert("swizzle_vec", "r");
+        data.insert("alpha_swizzle", "g");
+        data.insert("processing_t", "float");
+        data.insert("output_t", "vec4");
+        datas.push(data);
+    }
+
+    {
+        let mut data = HashMap::new();
+        //16-bit float grayscale with alpha
+        data.insert("output_format", "rg16f");
+        data.insert("swizzle_vec", "r");
+        data.insert("alpha_swizzle", "g");
+        data.insert("processing_t", "float");
+        data.insert("output_t", "vec4");
+        datas.push(data);
+    }
+
+    {
+        let mut data = HashMap::new();
+        //32-bit float rgba
+        data.insert("output_format", "rgba32f");
+        data.insert("swizzle_vec", "rgb");
+        data.insert("alpha_swizzle", "a");
+        data.insert("processing_t", "vec3");
+        data.insert("output_t", "vec4");
+        data.insert("is_vector_type", "true");
+        datas.push(data);
+    }
+
+    {
+        let mut data = HashMap::new();
+        //16-bit float rgba
+        data.insert("output_format", "rgba16f");
+        data.insert("swizzle_vec", "rgb");
+        data.insert("alpha_swizzle", "a");
+        data.insert("processing_t", "vec3");
+        data.insert("output_t", "vec4");
+        data.insert("is_vector_type", "true");
+        datas.push(data);
+    }
+
     let shader_typed_datas: Vec<HashMap<&str, &str>> = datas.into_iter().flat_map(|mut d| {
         let mut df = d.clone();
         d.insert("compute", "compute");
@@ -78,10 +161,23 @@ pub fn generate_shaders() {
 
     let shader_typed_datas_hsv: Vec<HashMap<&str, &str>> = shader_typed_datas.into_iter()
         .flat_map(|mut d| {
+            d.insert("color_space_enum_val", "ColorSpace::Rgb");
+            d.insert("weight_t", "float");
             if d.get("is_vector_type").eq(&Some(&"true")) {
                 let mut dhsv = d.clone();
                 dhsv.insert("is_hsv", "_hsv");
-                vec![d, dhsv]
+                let mut variants = vec![d.clone(), dhsv];
+                //Luma and chroma are weighted separately in these
+                for (suffix, flag, enum_val) in [("_ycbcr", "is_ycbcr", "ColorSpace::YCbCr"), ("_lab", "is_lab", "ColorSpace::Lab"),
+                                                 ("_oklab", "is_oklab", "ColorSpace::Oklab")] {
+                    let mut dcs = d.clone();
+                    dcs.insert("color_space", suffix);
+                    dcs.insert(flag, "true");
+                    dcs.insert("color_space_enum_val", enum_val);
+                    dcs.insert("weight_t", "vec3");
+                    variants.push(dcs);
+                }
+                variants
             } else {
                 vec![d]
             }
@@ -90,8 +186,15 @@ pub fn generate_shaders() {
     let format_pairs: HashMap::<String, String>  = vec![
         ("r8ui".to_string(), "R8_UINT".to_string()),
         ("r16ui".to_string(), "R16_UINT".to_string()),
+        ("rg8ui".to_string(), "R8G8_UINT".to_string()),
+        ("rg16ui".to_string(), "R16G16_UINT".to_string()),
         ("rgba8ui".to_string(), "R8G8B8A8_UINT".to_string()),
         ("r32f".to_string(), "R32_SFLOAT".to_string()),
+        ("r16f".to_string(), "R16_SFLOAT".to_string()),
+        ("rg32f".to_string(), "R32G32_SFLOAT".to_string()),
+        ("rg16f".to_string(), "R16G16_SFLOAT".to_string()),
+        ("rgba32f".to_string(), "R32G32B32A32_SFLOAT".to_string()),
+        ("rgba16f".to_string(), "R16G16B16A16_SFLOAT".to_string()),
         ("rgba16ui".to_string(), "R16G16B16A16_UINT".to_string())]
         .into_iter()
         .collect();
@@ -105,28 +208,52 @@ pub fn generate_shaders() {
 
 
     let mut handlebars = Handlebars::new();
+    handlebars
+        .register_template_file("shader_params", "templates/shader_params.mustache")
+        .unwrap();
+    handlebars
+        .register_template_file("shader_fetch", "templates/shader_fetch.mustache")
+        .unwrap();
+    handlebars
+        .register_template_file("shader_result", "templates/shader_result.mustache")
+        .unwrap();
 
-    for algorythm in ["Smart", "Radial"] {
+    for algorythm in ["Smart", "Radial", "NonLocalMeans"] {
         handlebars
             .register_template_file(algorythm, format!("templates/denoise_shader_{}.mustache", algorythm.to_lowercase()))
             .unwrap();
 
-        for d in shader_typed_datas_hsv.iter() {
+        let variants: Vec<HashMap<&str, &str>> = shader_typed_datas_hsv.iter().flat_map(|d| {
+            let mut variants = vec![d.clone()];
+            //Guides replace the colour comparison, so only plain RGB filtering has a guided variant
+            if algorythm == "Smart" && d.get("is_hsv").is_none() && d.get("color_space").is_none() {
+                let mut dg = d.clone();
+                dg.insert("guided", "_guided");
+                variants.push(dg);
+            }
+            variants
+        }).collect();
+
+        for d in variants.iter() {
             let shader = handlebars.render(algorythm, d).unwrap();
 
             let shadert = d.get("compute").unwrap_or(&"fragment");
             let shadert_enum_val = d.get("shadert_enum_val").unwrap();
 
-            let filtering_type = d.get("is_hsv").unwrap_or(&"");
+            let filtering_type = d.get("is_hsv").or(d.get("color_space")).or(d.get("guided")).unwrap_or(&"");
             let is_hsv = d.get("is_hsv").is_some();
+            let guided = d.get("guided").is_some();
+            let color_space_enum_val = d.get("color_space_enum_val").unwrap();
 
             let format = d.get("output_format").unwrap();
             let vk_type = format_pairs.get(*format).unwrap();
 
             let name = format!("denoise_shader_{}_{}{}{}", shadert, format, algorythm.to_lowercase(), filtering_type);
             shader_mods.push(format!("pub(crate) mod {};", &name));
-            let align = 48 - (vk_type.len() + shadert_enum_val.len() + is_hsv.to_string().len());
-            shader_matchers.push(format!("{:>12}(Format::{}, {}, {}, Algo::{}) => {:align$}{}::load(device.clone()),", "", vk_type, shadert_enum_val, is_hsv, algorythm, "", &name));
+            let align = 72 - (vk_type.len() + shadert_enum_val.len() + is_hsv.to_string().len() + color_space_enum_val.len()
+                + guided.to_string().len());
+            shader_matchers.push(format!("{:>12}(Format::{}, {}, {}, {}, Algo::{}, {}) => {:align$}{}::load(device.clone()),", "",
+                                         vk_type, shadert_enum_val, is_hsv, color_space_enum_val, algorythm, guided, "", &name));
             let mut file = File::create(format!("{}/{}.rs", base_path, &name)).unwrap();
             file.write_all(shader.as_bytes()).unwrap();
         }
@@ -143,15 +270,18 @@ use vulkano::device::Device;
 use vulkano::shader::ShaderModule;
 use crate::UsingShader;
 use crate::Algo;
+use crate::ColorSpace;
+use crate::DenoiseError;
 
-    pub(crate) fn get_denoise_shader(device: Arc<Device>, format: Format, shader_type: UsingShader, use_hsv: bool, algo: Algo) -> Arc<ShaderModule> {
-        match (format, shader_type, use_hsv, algo) {"#.as_bytes()).unwrap();
+    pub(crate) fn get_denoise_shader(device: Arc<Device>, format: Format, shader_type: UsingShader, use_hsv: bool, color_space: ColorSpace, algo: Algo, guided: bool) -> Result<Arc<ShaderModule>, DenoiseError> {
+        let shader = match (format, shader_type, use_hsv, color_space, algo, guided) {"#.as_bytes()).unwrap();
 
     file.write_all(shader_matchers.join("\n").as_bytes()).unwrap();
 
     file.write_all(r#"
-            _ => unimplemented!(),
-        }.unwrap()
+            _ => return Err(DenoiseError::PipelineCreation(format!("no {:?} shader for {:?}", shader_type, format))),
+        };
+        Ok(shader?)
     }
     "#.as_bytes()).unwrap();
 
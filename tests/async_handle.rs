@@ -0,0 +1,61 @@
+//! Handles have to resolve to what the blocking call returns, whether awaited or waited on, with several in flight.
+
+mod common;
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+use smart_denoise::{Algo, DenoiseError, DenoiseParams};
+use common::{backends, test_image};
+
+const WIDTH: u32 = 20;
+const HEIGHT: u32 = 14;
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Minimal executor, parks the test thread until the handle wakes it.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[test]
+fn handles_match_blocking_calls() {
+    let params = DenoiseParams::new(2.0, 2.0, 0.195);
+    let images: Vec<Vec<u8>> = (0..4).map(|seed| test_image(WIDTH, HEIGHT, 3, seed)).collect();
+    for (denoiser, shader_type) in backends() {
+        let handles: Vec<_> = images.iter()
+            .map(|img| denoiser.denoise_async(img, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart).unwrap())
+            .collect();
+        for (n, (img, handle)) in images.iter().zip(handles).enumerate() {
+            let expected = denoiser.denoise(img, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart).unwrap();
+            let res = if n.is_multiple_of(2) { handle.wait() } else { block_on(handle) };
+            assert!(res.unwrap() == expected, "{:?}: image {} differs from the blocking call", shader_type, n);
+        }
+    }
+}
+
+#[test]
+fn wrong_size_is_reported() {
+    let img = test_image(WIDTH, HEIGHT, 3, 0);
+    for (denoiser, shader_type) in backends() {
+        let res = denoiser.denoise_async(&img[..7], WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, Algo::Smart)
+            .and_then(|handle| handle.wait());
+        assert!(matches!(res, Err(DenoiseError::BufferSizeMismatch { .. })), "{:?}: wrong size accepted", shader_type);
+    }
+}
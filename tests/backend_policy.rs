@@ -0,0 +1,32 @@
+//! A denoiser that has to run on the GPU must not be bypassed through the CPU shader type.
+
+use smart_denoise::{Algo, BackendPolicy, DenoiseError, DenoiseParams, Denoiser, ImageRef, UsingShader};
+
+#[test]
+fn cpu_shader_type_is_rejected_when_gpu_is_required() {
+    let denoiser = match Denoiser::with_policy(BackendPolicy::RequireGpu) {
+        Ok(denoiser) => denoiser,
+        Err(e) => {
+            eprintln!("skipping: {}", e);
+            return;
+        }
+    };
+    let img = vec![128u8; 16 * 8];
+    let params = DenoiseParams::default();
+    let res = denoiser.denoise(&img, 16, 8, UsingShader::Cpu, params, false, Algo::Smart);
+    assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "denoise ran on the CPU");
+    let res = denoiser.denoise_async(&img, 16, 8, UsingShader::Cpu, params, false, Algo::Smart);
+    assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "denoise_async ran on the CPU");
+    let res = denoiser.denoise_batch(&[ImageRef::new(&img, 16, 8)], UsingShader::Cpu, params, false, Algo::Smart);
+    assert!(matches!(res[0], Err(DenoiseError::InvalidParameter(_))), "denoise_batch ran on the CPU");
+}
+
+#[test]
+fn cpu_only_accepts_every_shader_type() {
+    let denoiser = Denoiser::with_policy(BackendPolicy::CpuOnly).unwrap();
+    let img = vec![128u8; 16 * 8];
+    for shader_type in [UsingShader::Cpu, UsingShader::Compute, UsingShader::Fragment] {
+        let res = denoiser.denoise(&img, 16, 8, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+        assert_eq!(res, img, "{:?}", shader_type);
+    }
+}
@@ -0,0 +1,38 @@
+//! A batch has to give every image the same result as denoising it on its own, and a bad image only fails its own entry.
+
+mod common;
+
+use smart_denoise::{Algo, DenoiseError, DenoiseParams, ImageRef};
+use common::{backends, test_image};
+
+#[test]
+fn batch_matches_single_calls() {
+    let sizes = [(16, 12, 1), (9, 21, 3), (16, 12, 3), (30, 5, 4)];
+    let images: Vec<(Vec<u8>, u32, u32)> = sizes.iter().enumerate()
+        .map(|(seed, &(w, h, channels))| (test_image(w, h, channels, seed), w, h))
+        .collect();
+    let refs: Vec<ImageRef<u8>> = images.iter().map(|(buf, w, h)| ImageRef::new(buf, *w, *h)).collect();
+
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial] {
+            let params = DenoiseParams::new(2.0, 2.0, 0.195);
+            let results = denoiser.denoise_batch(&refs, shader_type, params, false, algo);
+            assert_eq!(results.len(), refs.len());
+            for (n, (img, res)) in refs.iter().zip(results).enumerate() {
+                let single = denoiser.denoise(img.buf, img.width, img.height, shader_type, params, false, algo).unwrap();
+                assert!(res.unwrap() == single, "{:?} {:?}: image {} differs from a single call", shader_type, algo, n);
+            }
+        }
+    }
+}
+
+#[test]
+fn failing_image_keeps_others() {
+    let good = test_image(8, 8, 4, 0);
+    let refs = [ImageRef::new(&good[..], 8, 8), ImageRef::new(&good[..10], 8, 8), ImageRef::new(&good[..], 8, 8)];
+    for (denoiser, shader_type) in backends() {
+        let results = denoiser.denoise_batch(&refs, shader_type, DenoiseParams::default(), false, Algo::Smart);
+        assert!(results[0].is_ok() && results[2].is_ok(), "{:?}: valid images failed", shader_type);
+        assert!(matches!(results[1], Err(DenoiseError::BufferSizeMismatch { .. })), "{:?}: wrong size accepted", shader_type);
+    }
+}
@@ -0,0 +1,93 @@
+//! Luma/chroma colour spaces have to smooth chroma noise with their own threshold while keeping luma edges.
+
+mod common;
+
+use smart_denoise::{Algo, ColorSpace, DenoiseError, DenoiseParams};
+use common::backends;
+
+const WIDTH: u32 = 24;
+const HEIGHT: u32 = 20;
+const COLOR_SPACES: [ColorSpace; 3] = [ColorSpace::YCbCr, ColorSpace::Lab, ColorSpace::Oklab];
+
+/// Gray step edge between 30 and 220 with red/blue noise that leaves the luma about unchanged.
+fn chroma_noise_image() -> Vec<u8> {
+    let mut img = Vec::with_capacity((WIDTH * HEIGHT) as usize * 3);
+    for y in 0..HEIGHT as usize {
+        for x in 0..WIDTH as usize {
+            let base = if x < WIDTH as usize / 2 { 30.0 } else { 220.0 };
+            let noise = ((x * 7919 + y * 104729) % 41) as f32 - 20.0;
+            img.extend([base + noise, base - noise * 0.1404 / 0.7152, base - noise].map(|v: f32| v.round() as u8));
+        }
+    }
+    img
+}
+
+/// Mean absolute red minus blue, zero for gray.
+fn chroma_noise(img: &[u8]) -> f32 {
+    img.chunks(3).map(|px| (px[0] as f32 - px[2] as f32).abs()).sum::<f32>() / (WIDTH * HEIGHT) as f32
+}
+
+/// Mean green in the columns right next to the edge.
+fn edge_contrast(img: &[u8]) -> f32 {
+    let column_mean = |x: usize| {
+        (0..HEIGHT as usize).map(|y| img[(y * WIDTH as usize + x) * 3 + 1] as f32).sum::<f32>() / HEIGHT as f32
+    };
+    column_mean(WIDTH as usize / 2) - column_mean(WIDTH as usize / 2 - 1)
+}
+
+#[test]
+fn flat_image_round_trips() {
+    let flat: Vec<u8> = (0..WIDTH * HEIGHT).flat_map(|i| [200, 120, 40, (i % 256) as u8]).collect();
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial] {
+            for color_space in COLOR_SPACES {
+                let params = DenoiseParams::default().with_color_space(color_space);
+                let res = denoiser.denoise(&flat, WIDTH, HEIGHT, shader_type, params, false, algo).unwrap();
+                let diff = flat.iter().zip(&res).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+                assert!(diff <= 1, "{:?} {:?} {:?}: flat image changed by {}", shader_type, algo, color_space, diff);
+            }
+        }
+    }
+}
+
+#[test]
+fn chroma_noise_is_removed_and_luma_edge_kept() {
+    let img = chroma_noise_image();
+    let params = DenoiseParams::new(3.0, 2.0, 0.1);
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial] {
+            let rgb = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, algo).unwrap();
+            for color_space in COLOR_SPACES {
+                let params = params.with_color_space(color_space).with_chroma(3.0, 1.0);
+                let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, algo).unwrap();
+                assert!(chroma_noise(&res) < chroma_noise(&rgb) * 0.6, "{:?} {:?} {:?}: chroma noise {} against {} in RGB",
+                        shader_type, algo, color_space, chroma_noise(&res), chroma_noise(&rgb));
+                assert!(edge_contrast(&res) > 150.0, "{:?} {:?} {:?}: edge contrast dropped to {}",
+                        shader_type, algo, color_space, edge_contrast(&res));
+            }
+        }
+    }
+}
+
+#[test]
+fn grayscale_ignores_color_space() {
+    let img: Vec<u8> = chroma_noise_image().chunks(3).map(|px| px[1]).collect();
+    for (denoiser, shader_type) in backends() {
+        let reference = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+        for color_space in COLOR_SPACES {
+            let params = DenoiseParams::default().with_color_space(color_space);
+            let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart).unwrap();
+            assert!(res == reference, "{:?} {:?}: grayscale result changed", shader_type, color_space);
+        }
+    }
+}
+
+#[test]
+fn hsv_with_color_space_is_rejected() {
+    let img = chroma_noise_image();
+    for (denoiser, shader_type) in backends() {
+        let params = DenoiseParams::default().with_color_space(ColorSpace::Lab);
+        let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, true, Algo::Smart);
+        assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "{:?} accepted HSV in Lab", shader_type);
+    }
+}
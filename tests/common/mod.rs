@@ -0,0 +1,55 @@
+//! Backends and images shared by the integration tests.
+#![allow(dead_code)]
+
+use smart_denoise::{BackendPolicy, Denoiser, UsingShader};
+
+/// The CPU, then both GPU paths when there's a device.
+pub fn backends() -> Vec<(Denoiser, UsingShader)> {
+    let mut backends = vec![(Denoiser::with_policy(BackendPolicy::CpuOnly).unwrap(), UsingShader::Cpu)];
+    for shader_type in [UsingShader::Compute, UsingShader::Fragment] {
+        match Denoiser::with_policy(BackendPolicy::RequireGpu) {
+            Ok(denoiser) => backends.push((denoiser, shader_type)),
+            Err(e) => eprintln!("skipping {:?}: {}", shader_type, e),
+        }
+    }
+    backends
+}
+
+/// Noisy vertical step edge in 8-bit code values, alpha (second or fourth sample) is a pattern unrelated to the colour.
+///
+/// `seed` shifts the noise so several images of the same size differ.
+pub fn test_image(width: u32, height: u32, channels: usize, seed: usize) -> Vec<u8> {
+    let mut img = Vec::with_capacity(width as usize * height as usize * channels);
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            for c in 0..channels {
+                let is_alpha = (channels == 2 && c == 1) || (channels == 4 && c == 3);
+                let v = if is_alpha {
+                    (x * 11 + y * 7) % 256
+                } else {
+                    let base = if x < width as usize / 2 { 60 } else { 190 } + c * 15;
+                    let noise = (x * 7919 + y * 104729 + c * 31 + seed) % 41;
+                    base + noise - 20
+                };
+                img.push(v as u8);
+            }
+        }
+    }
+    img
+}
+
+/// Noisy checkerboard of 23 x 17 pixel cells, so tiles of any size cut through edges.
+pub fn checker_image(width: u32, height: u32, channels: usize) -> Vec<u8> {
+    (0..width as usize * height as usize * channels)
+        .map(|i| {
+            let (px, c) = (i / channels, i % channels);
+            let (x, y) = (px % width as usize, px / width as usize);
+            let base = if (x / 23 + y / 17).is_multiple_of(2) { 60 } else { 190 } + c * 15;
+            (base + (x * 7919 + y * 104729 + c * 31) % 41 - 20) as u8
+        })
+        .collect()
+}
+
+pub fn squared_error(a: &[u8], b: &[u8]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (*a as f32 - *b as f32).powi(2)).sum()
+}
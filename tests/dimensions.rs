@@ -0,0 +1,48 @@
+//! Every pixel has to be written whatever the image size, including sizes the compute workgroup doesn't divide.
+
+mod common;
+
+use smart_denoise::{Algo, BackendPolicy, DenoiseParams, Denoiser, NonLocalMeansParams, UsingShader};
+use common::{backends, test_image};
+
+const SIZES: [(u32, u32); 4] = [(1, 1), (7, 13), (13, 7), (1023, 769)];
+
+/// Small radii so the large image stays quick on the CPU
+fn params() -> DenoiseParams {
+    DenoiseParams::new(1.5, 2.0, 0.195)
+        .with_non_local_means(NonLocalMeansParams { patch_radius: 1, search_radius: 1, ..NonLocalMeansParams::default() })
+}
+
+#[test]
+fn flat_image_stays_flat() {
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial, Algo::NonLocalMeans] {
+            for channels in [1, 4] {
+                for (width, height) in SIZES {
+                    let buf = vec![200u8; (width * height) as usize * channels];
+                    let res = denoiser.denoise(&buf, width, height, shader_type, params(), false, algo).unwrap();
+                    assert_eq!(res.len(), buf.len());
+                    let wrong = res.iter().position(|v| *v != 200);
+                    assert!(wrong.is_none(), "{:?} {:?} {}x{}x{}: sample {} is {}",
+                            shader_type, algo, width, height, channels, wrong.unwrap(), res[wrong.unwrap()]);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn gpu_matches_cpu_on_odd_sizes() {
+    let cpu = Denoiser::with_policy(BackendPolicy::CpuOnly).unwrap();
+    for (denoiser, shader_type) in backends().into_iter().filter(|(_, shader_type)| *shader_type != UsingShader::Cpu) {
+        for algo in [Algo::Smart, Algo::Radial, Algo::NonLocalMeans] {
+            for (width, height) in SIZES {
+                let buf = test_image(width, height, 4, 0);
+                let expected = cpu.denoise(&buf, width, height, UsingShader::Cpu, params(), false, algo).unwrap();
+                let res = denoiser.denoise(&buf, width, height, shader_type, params(), false, algo).unwrap();
+                let worst = expected.iter().zip(&res).map(|(a, b)| (*a as i32 - *b as i32).abs()).max().unwrap();
+                assert!(worst <= 1, "{:?} {:?} {}x{}: differs from CPU by {}", shader_type, algo, width, height, worst);
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Every sample type and channel count has to give the same result as 8-bit data holding the same values,
+//! so `threshold` means the same at any bit depth. Runs on the CPU and, when there's a device, on both GPU paths.
+
+mod common;
+
+use smart_denoise::{f16, Algo, DenoiseError, DenoiseParams, Denoiseable, Denoiser, UsingShader};
+use common::{backends, test_image};
+
+const WIDTH: u32 = 24;
+const HEIGHT: u32 = 20;
+
+fn denoise_normalised<D: Denoiseable>(denoiser: &Denoiser, shader_type: UsingShader, algo: Algo, img: &[u8], channels: usize,
+                                      to_sample: impl Fn(u8) -> D, params: DenoiseParams) -> Result<Vec<f32>, DenoiseError> {
+    let buf: Vec<D> = img.iter().map(|v| to_sample(*v)).collect();
+    assert_eq!(buf.len(), (WIDTH * HEIGHT) as usize * channels);
+    let res = denoiser.denoise(&buf, WIDTH, HEIGHT, shader_type, params, false, algo)?;
+    Ok(res.into_iter().map(|v| v.as_() / D::MAX_VALUE).collect())
+}
+
+fn max_diff(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    a.iter().zip(b).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max)
+}
+
+/// Compares `D` against the u8 result for every backend, algorithm and channel count.
+fn check_against_u8<D: Denoiseable>(to_sample: impl Fn(u8) -> D + Copy, tolerance: f32) {
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial, Algo::NonLocalMeans] {
+            for channels in 1..=4 {
+                let img = test_image(WIDTH, HEIGHT, channels, 0);
+                let reference = denoise_normalised(&denoiser, shader_type, algo, &img, channels, |v| v, DenoiseParams::default()).unwrap();
+                let res = denoise_normalised(&denoiser, shader_type, algo, &img, channels, to_sample, DenoiseParams::default()).unwrap();
+                let diff = max_diff(&reference, &res);
+                assert!(diff <= tolerance, "{} {:?} {:?} {} channel(s): differs from u8 by {}",
+                        std::any::type_name::<D>(), shader_type, algo, channels, diff);
+            }
+        }
+    }
+}
+
+#[test]
+fn u16_matches_u8() {
+    check_against_u8(|v| v as u16 * 257, 1.0 / 255.0);
+}
+
+#[test]
+fn f32_matches_u8() {
+    check_against_u8(|v| v as f32 / 255.0, 1.0 / 255.0);
+}
+
+#[test]
+fn f16_matches_u8() {
+    check_against_u8(|v| f16::from_f32(v as f32 / 255.0), 2.0 / 255.0);
+}
+
+#[test]
+fn alpha_is_preserved() {
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial, Algo::NonLocalMeans] {
+            for channels in [2, 4] {
+                let img = test_image(WIDTH, HEIGHT, channels, 0);
+                let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, algo).unwrap();
+                let alpha_in = img.iter().skip(channels - 1).step_by(channels);
+                let alpha_out = res.iter().skip(channels - 1).step_by(channels);
+                assert!(alpha_in.eq(alpha_out), "{:?} {:?} {} channels: alpha changed", shader_type, algo, channels);
+            }
+        }
+    }
+}
+
+#[test]
+fn hsv_is_ignored_for_grayscale() {
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial, Algo::NonLocalMeans] {
+            for channels in [1, 2] {
+                let img = test_image(WIDTH, HEIGHT, channels, 0);
+                let reference = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, algo).unwrap();
+                let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), true, algo).unwrap();
+                assert!(reference == res, "{:?} {:?} {} channel(s): HSV changed a grayscale result", shader_type, algo, channels);
+            }
+        }
+    }
+}
+
+#[test]
+fn white_level_scales_low_bit_depths() {
+    for (denoiser, shader_type) in backends() {
+        for bits in [10, 12] {
+            let white_level = ((1u32 << bits) - 1) as f32;
+            let scale = white_level / 255.0;
+            for channels in 1..=4 {
+                let img = test_image(WIDTH, HEIGHT, channels, 0);
+                let reference = denoise_normalised(&denoiser, shader_type, Algo::Smart, &img, channels,
+                                                   |v| (v as f32 * scale).round() / white_level, DenoiseParams::default()).unwrap();
+                let buf: Vec<u16> = img.iter().map(|v| (*v as f32 * scale).round() as u16).collect();
+                let params = DenoiseParams::default().with_white_level(white_level);
+                let res = denoiser.denoise(&buf, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart).unwrap();
+                assert!(res.iter().all(|v| *v as f32 <= white_level), "{:?} {} bits: value above white level", shader_type, bits);
+                let res: Vec<f32> = res.into_iter().map(|v| v as f32 / white_level).collect();
+                let diff = max_diff(&reference, &res);
+                assert!(diff <= 1.0 / white_level, "{:?} {} bits {} channel(s): differs from f32 by {}", shader_type, bits, channels, diff);
+            }
+        }
+    }
+}
+
+#[test]
+fn invalid_white_level_is_rejected() {
+    let img = test_image(WIDTH, HEIGHT, 1, 0);
+    for (denoiser, shader_type) in backends() {
+        for white_level in [0.0, -1.0, f32::NAN] {
+            let params = DenoiseParams::default().with_white_level(white_level);
+            let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart);
+            assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "{:?} accepted white level {}", shader_type, white_level);
+        }
+    }
+}
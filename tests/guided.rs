@@ -0,0 +1,90 @@
+//! Guided filtering has to take its edges from the guides, in every shader, and reject guides that don't fit the image.
+
+mod common;
+
+use smart_denoise::{Algo, ColorSpace, DenoiseError, DenoiseParams, Guide};
+use common::{backends, squared_error};
+
+const WIDTH: u32 = 24;
+const HEIGHT: u32 = 20;
+
+/// Quadrants split at `x == 12` and `y == 10` with less contrast than the default threshold, with or without noise.
+fn quadrants(channels: usize, noisy: bool) -> Vec<u8> {
+    let mut img = Vec::with_capacity((WIDTH * HEIGHT) as usize * channels);
+    for y in 0..HEIGHT as usize {
+        for x in 0..WIDTH as usize {
+            for c in 0..channels {
+                let base = 90 + if x < 12 { 0 } else { 40 } + if y < 10 { 0 } else { 20 } + c * 10;
+                let noise = if noisy { (x * 7919 + y * 104729 + c * 31) % 41 } else { 20 };
+                img.push((base + noise - 20) as u8);
+            }
+        }
+    }
+    img
+}
+
+/// Clean step along x like an albedo pass, 3 channels.
+fn albedo() -> Vec<f32> {
+    (0..WIDTH * HEIGHT).flat_map(|i| if i % WIDTH < 12 { [0.2, 0.3, 0.4] } else { [0.7, 0.6, 0.5] }).collect()
+}
+
+/// Clean step along y like a depth pass, 1 channel in scene units.
+fn depth() -> Vec<f32> {
+    (0..WIDTH * HEIGHT).map(|i| if i / WIDTH < 10 { 5.0 } else { 12.0 }).collect()
+}
+
+#[test]
+fn edges_come_from_every_guide() {
+    let clean = quadrants(3, false);
+    let img = quadrants(3, true);
+    let (albedo, depth) = (albedo(), depth());
+    let guides = [Guide::new(&albedo, 0.05), Guide::new(&depth, 0.5)];
+    for (denoiser, shader_type) in backends() {
+        let smart = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+        let res = denoiser.denoise_guided(&img, WIDTH, HEIGHT, &guides, shader_type, DenoiseParams::default()).unwrap();
+        assert!(squared_error(&res, &clean) < squared_error(&img, &clean) * 0.3, "{:?}: squared error {} after, {} before",
+                shader_type, squared_error(&res, &clean), squared_error(&img, &clean));
+        assert!(squared_error(&res, &clean) < squared_error(&smart, &clean) * 0.5, "{:?}: squared error {} against {} of Smart",
+                shader_type, squared_error(&res, &clean), squared_error(&smart, &clean));
+
+        //Without the depth the horizontal edge gets blurred
+        let albedo_only = denoiser.denoise_guided(&img, WIDTH, HEIGHT, &guides[..1], shader_type, DenoiseParams::default()).unwrap();
+        assert!(squared_error(&res, &clean) < squared_error(&albedo_only, &clean) * 0.5, "{:?}: depth guide made no difference", shader_type);
+    }
+}
+
+#[test]
+fn input_as_guide_matches_smart() {
+    let img = quadrants(3, true);
+    let normalised: Vec<f32> = img.iter().map(|v| *v as f32 / 255.0).collect();
+    let params = DenoiseParams::new(3.0, 2.0, 0.15);
+    for (denoiser, shader_type) in backends() {
+        let smart = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart).unwrap();
+        let res = denoiser.denoise_guided(&img, WIDTH, HEIGHT, &[Guide::new(&normalised, 0.15)], shader_type, params).unwrap();
+        let diff = smart.iter().zip(&res).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+        assert!(diff <= 1, "{:?}: guided by itself differs from Smart by {}", shader_type, diff);
+    }
+}
+
+#[test]
+fn invalid_guides_are_rejected() {
+    let img = quadrants(4, true);
+    let depth = depth();
+    let five_channels = vec![0.0; (WIDTH * HEIGHT) as usize * 5];
+    for (denoiser, shader_type) in backends() {
+        let guided = |guides: &[Guide], params| denoiser.denoise_guided(&img, WIDTH, HEIGHT, guides, shader_type, params);
+        let params = DenoiseParams::default();
+        assert!(matches!(guided(&[], params), Err(DenoiseError::InvalidParameter(_))), "{:?} accepted no guide", shader_type);
+        assert!(matches!(guided(&[Guide::new(&depth[1..], 0.5)], params), Err(DenoiseError::BufferSizeMismatch { .. })),
+                "{:?} accepted a short guide", shader_type);
+        assert!(matches!(guided(&[Guide::new(&five_channels, 0.5)], params), Err(DenoiseError::UnsupportedChannelLayout { channels: 5, .. })),
+                "{:?} accepted 5 guide channels", shader_type);
+        for threshold in [0.0, -1.0, f32::NAN] {
+            assert!(matches!(guided(&[Guide::new(&depth, threshold)], params), Err(DenoiseError::InvalidParameter(_))),
+                    "{:?} accepted threshold {}", shader_type, threshold);
+        }
+        let lab = params.with_color_space(ColorSpace::Lab);
+        assert!(matches!(guided(&[Guide::new(&depth, 0.5)], lab), Err(DenoiseError::InvalidParameter(_))),
+                "{:?} accepted Lab", shader_type);
+    }
+}
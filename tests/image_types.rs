@@ -0,0 +1,49 @@
+//! `image` crate types have to come back with their variant and dimensions, filtered like the flat buffer.
+
+use image::{DynamicImage, ImageBuffer, Rgb};
+use smart_denoise::{BackendPolicy, Denoiser, Options, UsingShader};
+
+const WIDTH: u32 = 18;
+const HEIGHT: u32 = 11;
+
+fn test_image() -> DynamicImage {
+    DynamicImage::ImageRgba8(ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        let noise = ((x * 7919 + y * 104729) % 41) as u8;
+        let base = if x < WIDTH / 2 { 60 } else { 190 };
+        image::Rgba([base + noise, base - noise / 2, base, 255 - (x * 9) as u8])
+    }))
+}
+
+#[test]
+fn variants_are_kept() {
+    let denoiser = Denoiser::with_policy(BackendPolicy::CpuOnly).unwrap();
+    let options = Options { shader_type: UsingShader::Cpu, ..Options::default() };
+    let img = test_image();
+    let variants = [
+        img.clone(),
+        DynamicImage::ImageLuma8(img.to_luma8()),
+        DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        DynamicImage::ImageRgb8(img.to_rgb8()),
+        DynamicImage::ImageLuma16(img.to_luma16()),
+        DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        DynamicImage::ImageRgb16(img.to_rgb16()),
+        DynamicImage::ImageRgba16(img.to_rgba16()),
+        DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        DynamicImage::ImageRgba32F(img.to_rgba32f()),
+    ];
+    for variant in variants {
+        let res = denoiser.denoise_image(&variant, &options).unwrap();
+        assert_eq!(res.color(), variant.color());
+        assert_eq!((res.width(), res.height()), (WIDTH, HEIGHT));
+    }
+}
+
+#[test]
+fn buffer_matches_flat_call() {
+    let denoiser = Denoiser::with_policy(BackendPolicy::CpuOnly).unwrap();
+    let options = Options { shader_type: UsingShader::Cpu, ..Options::default() };
+    let img: ImageBuffer<Rgb<u16>, Vec<u16>> = test_image().to_rgb16();
+    let res = denoiser.denoise_buffer(&img, &options).unwrap();
+    let flat = denoiser.denoise(img.as_raw(), WIDTH, HEIGHT, options.shader_type, options.params, options.use_hsv, options.algo).unwrap();
+    assert!(res.into_raw() == flat);
+}
@@ -0,0 +1,78 @@
+//! Pitched and reordered buffers have to give the packed RGBA result, padding stays untouched and bad lengths are errors.
+
+mod common;
+
+use smart_denoise::{Algo, BackendPolicy, ChannelOrder, DenoiseError, DenoiseParams, Denoiser, Layout, UsingShader};
+use common::{backends, test_image};
+
+const WIDTH: u32 = 13;
+const HEIGHT: u32 = 9;
+/// Marks padding so writes into it show up
+const PAD: u16 = 0xBEEF;
+
+fn packed_image(channels: usize) -> Vec<u16> {
+    test_image(WIDTH, HEIGHT, channels, 0).iter().map(|v| *v as u16 * 257).collect()
+}
+
+/// Rows of `packed` with channels moved to `order` and `pad` samples appended.
+fn pitched(packed: &[u16], channels: usize, positions: &[usize], pad: usize) -> Vec<u16> {
+    packed.chunks(WIDTH as usize * channels)
+        .flat_map(|row| {
+            let mut out: Vec<u16> = row.chunks(channels)
+                .flat_map(|px| {
+                    let mut reordered = vec![0; channels];
+                    for (c, &pos) in positions.iter().enumerate() {
+                        reordered[pos] = px[c];
+                    }
+                    reordered
+                })
+                .collect();
+            out.extend(std::iter::repeat_n(PAD, pad));
+            out
+        })
+        .collect()
+}
+
+#[test]
+fn pitched_reordered_matches_packed() {
+    let cases = [(3, ChannelOrder::Bgra, vec![2, 1, 0]), (4, ChannelOrder::Bgra, vec![2, 1, 0, 3]),
+                 (4, ChannelOrder::Argb, vec![1, 2, 3, 0]), (4, ChannelOrder::Abgr, vec![3, 2, 1, 0]),
+                 (2, ChannelOrder::Argb, vec![1, 0]), (1, ChannelOrder::Rgba, vec![0])];
+    for (denoiser, shader_type) in backends() {
+        for (channels, order, positions) in &cases {
+            let packed = packed_image(*channels);
+            let expected = denoiser.denoise(&packed, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+
+            let pad = 3;
+            let input = pitched(&packed, *channels, positions, pad);
+            let mut output = vec![PAD; input.len()];
+            let layout = Layout::new(WIDTH, HEIGHT, *channels)
+                .with_row_stride((WIDTH as usize * channels + pad) * 2)
+                .with_channel_order(*order);
+            denoiser.denoise_into(&input, &mut output, &layout, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+            assert!(output == pitched(&expected, *channels, positions, pad), "{:?} {} channel(s) {:?}: differs from packed",
+                    shader_type, channels, order);
+        }
+    }
+}
+
+#[test]
+fn inconsistent_layouts_are_rejected() {
+    let denoiser = Denoiser::with_policy(BackendPolicy::CpuOnly).unwrap();
+    let input = packed_image(3);
+    let mut output = vec![0u16; input.len()];
+    let denoise = |layout: Layout, input: &[u16], output: &mut [u16]| {
+        denoiser.denoise_into(input, output, &layout, UsingShader::Cpu, DenoiseParams::default(), false, Algo::Smart)
+    };
+
+    let res = denoise(Layout::new(WIDTH, HEIGHT, 4), &input, &mut output);
+    assert!(matches!(res, Err(DenoiseError::BufferSizeMismatch { .. })), "length of another channel count accepted");
+    let res = denoise(Layout::new(WIDTH, HEIGHT, 3), &input, &mut output[1..]);
+    assert!(matches!(res, Err(DenoiseError::BufferSizeMismatch { .. })), "short output accepted");
+    let res = denoise(Layout::new(WIDTH, HEIGHT, 3).with_row_stride(WIDTH as usize * 6 + 1), &input, &mut output);
+    assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "stride splitting a sample accepted");
+    let res = denoise(Layout::new(WIDTH, HEIGHT, 3).with_row_stride(WIDTH as usize * 6 - 2), &input, &mut output);
+    assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "stride shorter than a row accepted");
+    let res = denoise(Layout::new(WIDTH, HEIGHT, 3).with_channel_order(ChannelOrder::Argb), &input, &mut output);
+    assert!(matches!(res, Err(DenoiseError::UnsupportedChannelLayout { .. })), "ARGB without alpha accepted");
+}
@@ -0,0 +1,115 @@
+//! Noise estimation has to find the level of known Gaussian noise on textured images and turn it into usable parameters.
+
+use smart_denoise::{estimate_linear_noise, estimate_noise, Algo, BackendPolicy, DenoiseError, Denoiser, TransferFunction, UsingShader};
+
+const WIDTH: u32 = 96;
+const HEIGHT: u32 = 80;
+
+/// Standard normal samples from a fixed seed through Box-Muller.
+fn gaussian(seed: u64) -> impl Iterator<Item = f32> {
+    let mut state = seed;
+    let mut uniform = move || {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((state >> 40) as f32 + 0.5) / (1u64 << 24) as f32
+    };
+    std::iter::repeat_with(move || {
+        let (u1, u2) = (uniform(), uniform());
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    })
+}
+
+/// Gradient with a few edges plus noise of `sigma[c]` in channel `c`, samples relative to full scale.
+fn noisy_image(sigma: &[f32]) -> Vec<f32> {
+    let channels = sigma.len();
+    let mut noise = gaussian(42);
+    let mut img = Vec::with_capacity((WIDTH * HEIGHT) as usize * channels);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let base = 0.2 + 0.4 * x as f32 / WIDTH as f32 + if (x / 25 + y / 21).is_multiple_of(2) { 0.2 } else { 0.0 };
+            img.extend(sigma.iter().map(|s| base + s * noise.next().unwrap()));
+        }
+    }
+    img
+}
+
+fn to_u8(img: &[f32]) -> Vec<u8> {
+    img.iter().map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8).collect()
+}
+
+fn to_u16(img: &[f32]) -> Vec<u16> {
+    img.iter().map(|v| (v * 65535.0).round().clamp(0.0, 65535.0) as u16).collect()
+}
+
+#[test]
+fn known_noise_is_found_per_channel() {
+    let sigma = [0.01, 0.03, 0.06];
+    let img = noisy_image(&sigma);
+    let estimates = [
+        estimate_noise(&img, WIDTH, HEIGHT).unwrap().relative(),
+        estimate_noise(&to_u8(&img), WIDTH, HEIGHT).unwrap().relative(),
+        estimate_noise(&to_u16(&img), WIDTH, HEIGHT).unwrap().relative(),
+    ];
+    for estimate in estimates {
+        for (found, expected) in estimate.iter().zip(sigma) {
+            assert!((found / expected - 1.0).abs() < 0.15, "estimated {:?} for {:?}", estimate, sigma);
+        }
+    }
+}
+
+#[test]
+fn clean_image_has_no_noise() {
+    let img = to_u8(&noisy_image(&[0.0, 0.0, 0.0]));
+    let estimate = estimate_noise(&img, WIDTH, HEIGHT).unwrap();
+    assert!(estimate.sigma.iter().all(|s| *s < 0.5), "estimated {:?} on a clean image", estimate.sigma);
+    //Parameters stay usable
+    let params = estimate.params();
+    assert!(params.sigma() > 0.0 && params.threshold() > 0.0, "{:?}", params);
+}
+
+#[test]
+fn stronger_noise_gets_stronger_params() {
+    let weak = estimate_noise(&to_u8(&noisy_image(&[0.01; 3])), WIDTH, HEIGHT).unwrap().params();
+    let strong = estimate_noise(&to_u8(&noisy_image(&[0.05; 3])), WIDTH, HEIGHT).unwrap().params();
+    assert!(strong.sigma() > weak.sigma() && strong.threshold() > weak.threshold(), "{:?} against {:?}", strong, weak);
+}
+
+#[test]
+fn alpha_is_left_out_of_params() {
+    let rgb = estimate_noise(&to_u8(&noisy_image(&[0.03; 3])), WIDTH, HEIGHT).unwrap();
+    let rgba = estimate_noise(&to_u8(&noisy_image(&[0.03, 0.03, 0.03, 0.2])), WIDTH, HEIGHT).unwrap();
+    assert_eq!(rgba.sigma.len(), 4);
+    assert!((rgba.params().threshold() / rgb.params().threshold() - 1.0).abs() < 0.1,
+            "alpha noise moved threshold from {} to {}", rgb.params().threshold(), rgba.params().threshold());
+}
+
+#[test]
+fn auto_params_remove_noise() {
+    let clean = to_u8(&noisy_image(&[0.0]));
+    let img = to_u8(&noisy_image(&[0.04]));
+    let params = estimate_noise(&img, WIDTH, HEIGHT).unwrap().params();
+    let error = |res: &[u8]| res.iter().zip(&clean).map(|(a, b)| (*a as f32 - *b as f32).powi(2)).sum::<f32>();
+    let denoiser = Denoiser::with_policy(BackendPolicy::CpuOnly).unwrap();
+    let res = denoiser.denoise(&img, WIDTH, HEIGHT, UsingShader::Cpu, params, false, Algo::Smart).unwrap();
+    assert!(error(&res) < error(&img) * 0.5, "squared error {} after, {} before", error(&res), error(&img));
+}
+
+#[test]
+fn invalid_sizes_are_rejected() {
+    let img = vec![0u8; 30];
+    assert!(matches!(estimate_noise(&img, 4, 4), Err(DenoiseError::BufferSizeMismatch { .. })));
+    assert!(matches!(estimate_noise(&img, 30, 1), Err(DenoiseError::InvalidParameter(_))));
+}
+
+#[test]
+fn srgb_input_is_estimated_in_linear_light() {
+    let sigma = [0.02; 3];
+    let linear = noisy_image(&sigma);
+    let encoded: Vec<f32> = linear.iter().map(|v| TransferFunction::Srgb.from_linear(*v)).collect();
+    let reference = estimate_noise(&to_u16(&linear), WIDTH, HEIGHT).unwrap().params();
+    for estimate in [estimate_linear_noise(&to_u8(&encoded), WIDTH, HEIGHT, TransferFunction::Srgb, None).unwrap(),
+                     estimate_linear_noise(&to_u16(&encoded), WIDTH, HEIGHT, TransferFunction::Srgb, None).unwrap()] {
+        let params = estimate.params();
+        assert!((params.threshold() / reference.threshold() - 1.0).abs() < 0.15,
+                "threshold {} from sRGB samples, {} from linear ones", params.threshold(), reference.threshold());
+    }
+}
@@ -0,0 +1,76 @@
+//! Non-local means has to keep repeated fine texture that a spatial filter blurs, in every filtering variant.
+
+mod common;
+
+use smart_denoise::{Algo, ColorSpace, DenoiseError, DenoiseParams, NonLocalMeansParams};
+use common::{backends, squared_error};
+
+const WIDTH: u32 = 24;
+const HEIGHT: u32 = 20;
+
+/// Vertical stripes two pixels wide with less contrast than the default threshold, with or without noise.
+fn stripes(channels: usize, noisy: bool) -> Vec<u8> {
+    let mut img = Vec::with_capacity((WIDTH * HEIGHT) as usize * channels);
+    for y in 0..HEIGHT as usize {
+        for x in 0..WIDTH as usize {
+            for c in 0..channels {
+                let base = if (x / 2).is_multiple_of(2) { 100 } else { 140 } + c * 10;
+                let noise = if noisy { (x * 7919 + y * 104729 + c * 31) % 41 } else { 20 };
+                img.push((base + noise - 20) as u8);
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn repeated_texture_is_kept() {
+    let clean = stripes(3, false);
+    let img = stripes(3, true);
+    for (denoiser, shader_type) in backends() {
+        let smart = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+        let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, Algo::NonLocalMeans).unwrap();
+        assert!(squared_error(&res, &clean) < squared_error(&img, &clean) * 0.4, "{:?}: squared error {} after, {} before",
+                shader_type, squared_error(&res, &clean), squared_error(&img, &clean));
+        assert!(squared_error(&res, &clean) < squared_error(&smart, &clean) * 0.5, "{:?}: squared error {} against {} of Smart",
+                shader_type, squared_error(&res, &clean), squared_error(&smart, &clean));
+    }
+}
+
+#[test]
+fn flat_image_round_trips_in_every_variant() {
+    let flat: Vec<u8> = (0..WIDTH * HEIGHT).flat_map(|i| [200, 120, 40, (i % 256) as u8]).collect();
+    let variants = [(false, ColorSpace::Rgb), (true, ColorSpace::Rgb), (false, ColorSpace::YCbCr), (false, ColorSpace::Lab),
+                    (false, ColorSpace::Oklab)];
+    for (denoiser, shader_type) in backends() {
+        for (use_hsv, color_space) in variants {
+            let params = DenoiseParams::default().with_color_space(color_space);
+            let res = denoiser.denoise(&flat, WIDTH, HEIGHT, shader_type, params, use_hsv, Algo::NonLocalMeans).unwrap();
+            let diff = flat.iter().zip(&res).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+            assert!(diff <= 1, "{:?} hsv {} {:?}: flat image changed by {}", shader_type, use_hsv, color_space, diff);
+        }
+    }
+}
+
+#[test]
+fn zero_search_radius_keeps_the_image() {
+    let img = stripes(4, true);
+    let params = DenoiseParams::default().with_non_local_means(NonLocalMeansParams { search_radius: 0, ..NonLocalMeansParams::default() });
+    for (denoiser, shader_type) in backends() {
+        let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::NonLocalMeans).unwrap();
+        assert!(res == img, "{:?}: image changed without neighbours", shader_type);
+    }
+}
+
+#[test]
+fn invalid_strength_is_rejected() {
+    let img = stripes(3, true);
+    for (denoiser, shader_type) in backends() {
+        for strength in [0.0, -0.1, f32::NAN] {
+            let non_local_means = NonLocalMeansParams { strength, ..NonLocalMeansParams::default() };
+            let params = DenoiseParams::default().with_non_local_means(non_local_means);
+            let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::NonLocalMeans);
+            assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "{:?} accepted strength {}", shader_type, strength);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Tuning of the Radial algorithm has to reach the shaders and be checked before it does.
+
+mod common;
+
+use smart_denoise::{Algo, DenoiseError, DenoiseParams, RadialParams};
+use common::backends;
+
+const WIDTH: u32 = 24;
+const HEIGHT: u32 = 20;
+
+/// Noisy background crossed by a one pixel wide diagonal line.
+fn thin_line_image(channels: usize) -> Vec<u8> {
+    let mut img = Vec::with_capacity((WIDTH * HEIGHT) as usize * channels);
+    for y in 0..HEIGHT as usize {
+        for x in 0..WIDTH as usize {
+            for c in 0..channels {
+                let base = if x == y + 2 { 200 } else { 60 };
+                img.push((base + (x * 7919 + y * 104729 + c * 31) % 17 - 8) as u8);
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn every_field_is_used() {
+    let defaults = RadialParams::default();
+    let tunings = [
+        RadialParams { small_radius: 3.0, ..defaults },
+        RadialParams { hsv_search_power: [1.0, 2.0], ..defaults },
+        RadialParams { hsv_filter_power: [1.0, 2.0], ..defaults },
+        RadialParams { angle_step: 4.0, ..defaults },
+        RadialParams { refine_step: 0.25, ..defaults },
+        RadialParams { fres_exponent: 1.0, ..defaults },
+        RadialParams { disperse_exponent: 2.0, ..defaults },
+        RadialParams { direction_divisor: 4.0, ..defaults },
+    ];
+    let img = thin_line_image(3);
+    let params = DenoiseParams::new(4.0, 2.0, 0.3);
+    for (denoiser, shader_type) in backends() {
+        let reference = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, true, Algo::Radial).unwrap();
+        for tuning in tunings {
+            let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params.with_radial(tuning), true, Algo::Radial).unwrap();
+            assert!(res != reference, "{:?}: {:?} didn't change the result", shader_type, tuning);
+        }
+    }
+}
+
+#[test]
+fn invalid_params_are_rejected() {
+    let img = thin_line_image(3);
+    let defaults = RadialParams::default();
+    for (denoiser, shader_type) in backends() {
+        for tuning in [RadialParams { angle_step: 0.0, ..defaults }, RadialParams { small_radius: f32::NAN, ..defaults },
+                       RadialParams { hsv_filter_power: [2.0, -1.0], ..defaults }, RadialParams { refine_step: 0.0, ..defaults }] {
+            let params = DenoiseParams::default().with_radial(tuning);
+            let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::Radial);
+            assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "{:?} accepted {:?}", shader_type, tuning);
+        }
+    }
+}
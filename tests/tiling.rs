@@ -0,0 +1,99 @@
+//! Images split in tiles by a small memory budget have to come out as in one pass, within a code value, for every border mode.
+//! Needs a Vulkan device, the CPU backend never tiles.
+
+mod common;
+
+use smart_denoise::{Algo, BackendPolicy, BorderMode, DenoiseParams, Denoiser, Guide, UsingShader};
+use common::checker_image;
+
+const WIDTH: u32 = 181;
+const HEIGHT: u32 = 97;
+
+#[test]
+fn tiles_match_single_pass() {
+    let (whole, tiled) = match (Denoiser::with_policy(BackendPolicy::RequireGpu), Denoiser::with_policy(BackendPolicy::RequireGpu)) {
+        (Ok(whole), Ok(tiled)) => (whole, tiled.with_memory_budget(128 * 1024)),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("skipping: {}", e);
+            return;
+        }
+    };
+    for shader_type in [UsingShader::Compute, UsingShader::Fragment] {
+        for algo in [Algo::Smart, Algo::Radial, Algo::NonLocalMeans] {
+            for border_mode in [BorderMode::Clamp, BorderMode::Mirror, BorderMode::Wrap, BorderMode::Constant] {
+                for channels in [1, 3, 4] {
+                    let img = checker_image(WIDTH, HEIGHT, channels);
+                    let params = DenoiseParams::default().with_border_mode(border_mode);
+                    let reference = whole.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, algo).unwrap();
+                    let res = tiled.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, algo).unwrap();
+                    let diff = reference.iter().zip(&res).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+                    assert!(diff <= 1, "{:?} {:?} {:?} {} channel(s): tiles differ from a single pass by {}",
+                            shader_type, algo, border_mode, channels, diff);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn guided_tiles_match_single_pass() {
+    //Guides take memory too, so the budget is larger to leave room for the overlap
+    let (whole, tiled) = match (Denoiser::with_policy(BackendPolicy::RequireGpu), Denoiser::with_policy(BackendPolicy::RequireGpu)) {
+        (Ok(whole), Ok(tiled)) => (whole, tiled.with_memory_budget(512 * 1024)),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("skipping: {}", e);
+            return;
+        }
+    };
+    let albedo: Vec<f32> = checker_image(WIDTH, HEIGHT, 3).iter().map(|v| *v as f32 / 255.0).collect();
+    let depth: Vec<f32> = (0..WIDTH * HEIGHT).map(|i| ((i % WIDTH) / 30 + (i / WIDTH) / 20) as f32).collect();
+    let guides = [Guide::new(&albedo, 0.2), Guide::new(&depth, 0.5)];
+    for shader_type in [UsingShader::Compute, UsingShader::Fragment] {
+        for border_mode in [BorderMode::Clamp, BorderMode::Mirror, BorderMode::Wrap, BorderMode::Constant] {
+            for channels in [1, 3, 4] {
+                let img = checker_image(WIDTH, HEIGHT, channels);
+                let params = DenoiseParams::default().with_border_mode(border_mode);
+                let reference = whole.denoise_guided(&img, WIDTH, HEIGHT, &guides, shader_type, params).unwrap();
+                let res = tiled.denoise_guided(&img, WIDTH, HEIGHT, &guides, shader_type, params).unwrap();
+                let diff = reference.iter().zip(&res).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+                assert!(diff <= 1, "{:?} {:?} {} channel(s): guided tiles differ from a single pass by {}",
+                        shader_type, border_mode, channels, diff);
+            }
+        }
+    }
+}
+
+#[test]
+fn budget_too_small_for_overlap_is_rejected() {
+    let denoiser = match Denoiser::with_policy(BackendPolicy::RequireGpu) {
+        Ok(denoiser) => denoiser.with_memory_budget(1024),
+        Err(e) => {
+            eprintln!("skipping: {}", e);
+            return;
+        }
+    };
+    let img = checker_image(WIDTH, HEIGHT, 1);
+    let res = denoiser.denoise(&img, WIDTH, HEIGHT, UsingShader::Compute, DenoiseParams::default(), false, Algo::Smart);
+    assert!(matches!(res, Err(smart_denoise::DenoiseError::InvalidParameter(_))));
+}
+
+#[test]
+fn tiles_in_flight_stay_within_budget() {
+    let budget = 128 * 1024;
+    let denoiser = match Denoiser::with_policy(BackendPolicy::RequireGpu) {
+        Ok(denoiser) => denoiser.with_memory_budget(budget),
+        Err(e) => {
+            eprintln!("skipping: {}", e);
+            return;
+        }
+    };
+    //Takes about 20 MiB in one pass, so a couple of hundred tiles
+    let (width, height) = (1024, 768);
+    let img = checker_image(width, height, 4);
+    for shader_type in [UsingShader::Compute, UsingShader::Fragment] {
+        denoiser.denoise(&img, width, height, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+        //The newest tile may push the ones in flight past the budget before the oldest is waited on
+        let peak = denoiser.peak_image_memory();
+        assert!(peak > 0 && peak <= 2 * budget, "{:?}: {} bytes in flight with a budget of {}", shader_type, peak, budget);
+    }
+}
@@ -0,0 +1,114 @@
+//! Colour has to be filtered in linear light when a transfer function is given and come back encoded the same way.
+
+mod common;
+
+use smart_denoise::{Algo, DenoiseError, DenoiseParams, TransferFunction};
+use common::backends;
+
+const WIDTH: u32 = 24;
+const HEIGHT: u32 = 20;
+const TRANSFER_FUNCTIONS: [TransferFunction; 3] = [TransferFunction::Srgb, TransferFunction::Rec709, TransferFunction::Gamma(2.2)];
+
+/// Noisy vertical edge between `left` and `right` code values, alpha (fourth sample) is a pattern unrelated to the colour.
+fn edge_image(left: u8, right: u8, channels: usize) -> Vec<u8> {
+    let mut img = Vec::with_capacity((WIDTH * HEIGHT) as usize * channels);
+    for y in 0..HEIGHT as usize {
+        for x in 0..WIDTH as usize {
+            for c in 0..channels {
+                let v = if channels == 4 && c == 3 {
+                    (x * 11 + y * 7) % 256
+                } else {
+                    let base = if x < WIDTH as usize / 2 { left } else { right } as usize;
+                    (base + (x * 7919 + y * 104729 + c * 31) % 9).saturating_sub(4)
+                };
+                img.push(v as u8);
+            }
+        }
+    }
+    img
+}
+
+/// Mean code value of the first channel in the columns right next to the edge.
+fn edge_contrast(img: &[u8], channels: usize) -> f32 {
+    let column_mean = |x: usize| {
+        (0..HEIGHT as usize).map(|y| img[(y * WIDTH as usize + x) * channels] as f32).sum::<f32>() / HEIGHT as f32
+    };
+    column_mean(WIDTH as usize / 2) - column_mean(WIDTH as usize / 2 - 1)
+}
+
+#[test]
+fn flat_image_round_trips() {
+    for (denoiser, shader_type) in backends() {
+        for transfer_function in TRANSFER_FUNCTIONS {
+            for channels in [1, 3, 4] {
+                let img = edge_image(10, 10, channels);
+                let flat: Vec<u8> = img.iter().enumerate().map(|(i, v)| if channels == 4 && i % 4 == 3 { *v } else { 10 }).collect();
+                let params = DenoiseParams::default().with_transfer_function(transfer_function);
+                let res = denoiser.denoise(&flat, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart).unwrap();
+                let diff = flat.iter().zip(&res).map(|(a, b)| a.abs_diff(*b)).max().unwrap();
+                assert!(diff <= 1, "{:?} {:?} {} channel(s): flat image changed by {}", shader_type, transfer_function, channels, diff);
+            }
+        }
+    }
+}
+
+#[test]
+fn unit_gamma_matches_linear() {
+    for (denoiser, shader_type) in backends() {
+        for algo in [Algo::Smart, Algo::Radial] {
+            let img = edge_image(60, 190, 4);
+            let reference = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, algo).unwrap();
+            let params = DenoiseParams::default().with_transfer_function(TransferFunction::Gamma(1.0));
+            let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, algo).unwrap();
+            assert!(reference.iter().zip(&res).all(|(a, b)| a.abs_diff(*b) <= 1), "{:?} {:?}: gamma 1 differs from linear", shader_type, algo);
+        }
+    }
+}
+
+#[test]
+fn highlight_edge_is_kept_in_linear_light() {
+    //Code values 200 and 240 differ by less than the default threshold, in linear light they differ by more
+    for (denoiser, shader_type) in backends() {
+        for channels in [1, 3] {
+            let img = edge_image(200, 240, channels);
+            let encoded = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, DenoiseParams::default(), false, Algo::Smart).unwrap();
+            let params = DenoiseParams::default().with_transfer_function(TransferFunction::Srgb);
+            let linear = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart).unwrap();
+            assert!(edge_contrast(&linear, channels) > edge_contrast(&encoded, channels) + 5.0,
+                    "{:?} {} channel(s): edge contrast {} in linear light, {} in code values",
+                    shader_type, channels, edge_contrast(&linear, channels), edge_contrast(&encoded, channels));
+        }
+    }
+}
+
+#[test]
+fn transfer_functions_invert() {
+    for transfer_function in TRANSFER_FUNCTIONS {
+        for i in 0..=100 {
+            let v = i as f32 / 50.0 - 0.5;
+            let back = transfer_function.from_linear(transfer_function.to_linear(v));
+            assert!((back - v).abs() < 1e-4, "{:?}: {} comes back as {}", transfer_function, v, back);
+        }
+    }
+}
+
+#[test]
+fn transfer_function_parses() {
+    assert_eq!("srgb".parse(), Ok(TransferFunction::Srgb));
+    assert_eq!("rec709".parse(), Ok(TransferFunction::Rec709));
+    assert_eq!("linear".parse(), Ok(TransferFunction::Linear));
+    assert_eq!("2.4".parse(), Ok(TransferFunction::Gamma(2.4)));
+    assert!("adobe".parse::<TransferFunction>().is_err());
+}
+
+#[test]
+fn invalid_gamma_is_rejected() {
+    let img = edge_image(60, 190, 3);
+    for (denoiser, shader_type) in backends() {
+        for gamma in [0.0, -2.2, f32::INFINITY] {
+            let params = DenoiseParams::default().with_transfer_function(TransferFunction::Gamma(gamma));
+            let res = denoiser.denoise(&img, WIDTH, HEIGHT, shader_type, params, false, Algo::Smart);
+            assert!(matches!(res, Err(DenoiseError::InvalidParameter(_))), "{:?} accepted gamma {}", shader_type, gamma);
+        }
+    }
+}